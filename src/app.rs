@@ -3,19 +3,77 @@
 /// in charge of starting the metronome thread and keeping a reference to it's handle
 // App.rs is loosely based on the ratatui JSON editor tutorial found here: https://ratatui.rs/tutorials/json-editor/app/
 use crate::{
+    audio::{self, AudioBackend, AudioOutput, AudioSink},
+    config::Config,
+    keymap::{self, Action},
     menu::Menu,
-    metronome::{InitMetronomeSettings, Metronome, MetronomeSettings},
+    metronome::{
+        tick_gain, InitMetronomeSettings, Metronome, MetronomeSettings, TickKind, LATENCY_RANGE,
+    },
+    midi,
+    processing::SoundProcessing,
+    setlist::{Preset, Setlist},
+    song_map::{parse_section, SongMap},
+    sounds::{find_sounds, insert_file, load_sound_file, sound_dirs, Sound, SoundWatcher},
+    synth::{DECAY_RANGE, PITCH_RANGE},
+    timing::TimingLog,
 };
 use atomic_float::AtomicF64;
 use color_eyre::{eyre::eyre, Report, Result};
-use crossterm::event::{KeyCode, KeyEvent};
-use std::sync::Arc;
+use crossterm::event::{KeyCode, KeyEvent, MouseButton, MouseEvent, MouseEventKind};
+use ratatui::layout::{Position, Rect};
+use rodio::source::Source;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{
-    fs,
-    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
 };
 
+// How long the highlight has to rest on a sound before it is previewed, so scrolling through the list stays quiet
+const PREVIEW_DELAY: Duration = Duration::from_millis(300);
+// How many taps it takes before the calibration screen suggests a latency offset
+pub const MIN_CALIBRATION_TAPS: usize = 8;
+// How long the beat indicator flashes for on beat 1
+const FLASH_LENGTH: Duration = Duration::from_millis(150);
+// The items of the edit menu, in the order refresh_edit_menu lists them
+const PLAYING_ITEM: usize = 0;
+const BPM_ITEM: usize = 1;
+const VOLUME_ITEM: usize = 2;
+const SOUND_ITEM: usize = 3;
+const TIME_SIGNATURE_ITEM: usize = 4;
+const BAR_COUNT_ITEM: usize = 5;
+const SONG_MAP_ITEM: usize = 6;
+const SYNTH_PITCH_ITEM: usize = 7;
+const SYNTH_DECAY_ITEM: usize = 8;
+const TRIM_ITEM: usize = 9;
+const NORMALIZE_ITEM: usize = 10;
+const SAMPLE_PITCH_ITEM: usize = 11;
+const AUDIO_OUTPUT_ITEM: usize = 12;
+const LATENCY_OFFSET_ITEM: usize = 13;
+const CALIBRATION_ITEM: usize = 14;
+const FLASH_ITEM: usize = 15;
+const MUTED_ITEM: usize = 16;
+const BACK_ITEM: usize = 17;
+
+// What the beat indicator panel shows, one cell per beat of the bar
+pub struct BeatIndicator {
+    pub beats: u64,
+    pub current: Option<u64>, // index of the beat being shown, None while stopped
+    pub accents: u64,         // bit mask of the accented beats (bit 0 is the first beat)
+    pub flash: bool,          // flash the whole panel, on the downbeat when flash_downbeat is on
+}
+
+// Where the clickable parts of the interface were last drawn, filled in by ui.rs so mouse events can be matched to them
+#[derive(Default)]
+pub struct ClickAreas {
+    pub main_menu: Rect,
+    pub right_panel: Rect,
+    pub beat_cells: Vec<Rect>,
+}
+
 // These two enums are used extensively in events.rs and ui.rs to render the correct state and
 // select the right value when editing
 #[derive(PartialEq)]
@@ -24,6 +82,10 @@ pub enum CurrentScreen {
     Editing,
     Exiting,
     SoundSelection,
+    SongMap,
+    AudioSettings,
+    AudioError,
+    Calibration,
     Error,
 }
 
@@ -31,6 +93,12 @@ pub enum CurrentScreen {
 pub enum CurrentlyEditing {
     Bpm,
     Volume,
+    SynthPitch,
+    SynthDecay,
+    SamplePitch,
+    LatencyOffset,
+    SoundPath,
+    Section(usize), // index of the song map section being edited, one past the end adds a new section
 }
 
 pub struct App {
@@ -43,10 +111,29 @@ pub struct App {
     pub main_menu: Menu,
     pub edit_menu: Menu,
     pub sound_selection_menu: Menu,
+    pub song_map_menu: Menu,
+    pub audio_menu: Menu,
+    pub audio_devices: Vec<String>, // output devices of the chosen host, as listed in the audio menu
     pub should_quit: bool,
     pub first_edit: bool, // this is used to overwrite the original metronome setting text upon opening the edit window
-    pub sound_list: Vec<String>,
+    pub sound_dirs: Vec<PathBuf>, // folders searched for sounds, in order
+    pub skipped_sounds: Vec<String>, // why each file in the sound folders couldn't be used as a sound
+    pub added_sounds: Vec<Sound>, // sounds added by path, kept when the sound folders are searched again
+    sound_watcher: Option<SoundWatcher>,
+    pub sound_menu_rows: Vec<Option<usize>>, // index in the sound_list of each row of the sound selection menu
+    pub preview_request: Option<(Instant, usize)>, // sound to preview once the highlight has rested on it
+    preview_output: Option<Box<dyn AudioSink>>, // opened on the first preview, separate from the metronome
     pub tick_rate: u64,
+    pub setlist: Option<Setlist>,
+    pub song_path: Option<String>,
+    pub config_path: Option<PathBuf>, // where settings are saved between runs, None to not save them
+    pub calibration_taps: Vec<f64>, // how many ms after (or before when negative) the click each calibration tap was
+    pub flash_downbeat: bool,
+    shown_beat: (u64, u64, Instant), // the bar and beat count last shown by the beat indicator, and when it changed
+    pub stage_mode: bool,            // show the tempo and position full screen in big digits
+    pub terminal_size: (u16, u16), // width and height of the terminal, kept up to date as it is resized
+    pub click_areas: ClickAreas,
+    pub help_scroll: Option<u16>, // how far the help overlay is scrolled down, None when it is closed
 }
 
 impl App {
@@ -57,19 +144,39 @@ impl App {
                 ns_delay: Arc::new(AtomicU64::new(500_000_000)),
                 ts_note: Arc::new(AtomicU64::new(init_settings.ts_note)),
                 ts_value: Arc::new(AtomicU64::new(init_settings.ts_value)),
-                ts_triplets: Arc::new(AtomicBool::new(false)),
-                sub_eights: Arc::new(AtomicBool::new(false)),
-                sub_sixteens: Arc::new(AtomicBool::new(false)),
+                ts_triplets: Arc::new(AtomicBool::new(init_settings.ts_triplets)),
+                sub_eights: Arc::new(AtomicBool::new(init_settings.sub_eights)),
+                sub_sixteens: Arc::new(AtomicBool::new(init_settings.sub_sixteens)),
                 current_beat_count: Arc::new(AtomicU64::new(0)),
                 beats_per_bar: Arc::new(AtomicU64::new(4)),
                 bar_count: Arc::new(AtomicU64::new(1)),
                 is_running: Arc::new(AtomicBool::new(init_settings.is_running)),
+                muted: Arc::new(AtomicBool::new(false)),
                 volume: Arc::new(AtomicF64::new(init_settings.volume)),
-                sound_list: Vec::new(),
+                sound_list: Arc::new(RwLock::new(Vec::new())),
                 selected_sound: Arc::new(AtomicUsize::new(0)),
+                synth_pitch: Arc::new(AtomicF64::new(init_settings.synth_pitch)),
+                synth_decay: Arc::new(AtomicF64::new(init_settings.synth_decay)),
+                sound_processing: Arc::new(RwLock::new(BTreeMap::new())),
+                sample_cache: Arc::new(Mutex::new(Vec::new())),
+                audio_backend: Arc::new(RwLock::new(AudioBackend::default())),
+                audio_output: Arc::new(RwLock::new(AudioOutput::default())),
+                reopen_output: Arc::new(AtomicBool::new(false)),
+                audio_error: Arc::new(RwLock::new(None)),
+                audio_warning: Arc::new(RwLock::new(None)),
+                latency_offset: Arc::new(AtomicI64::new(0)),
+                visual_beat_count: Arc::new(AtomicU64::new(0)),
+                visual_bar_count: Arc::new(AtomicU64::new(1)),
+                last_tick_time: Arc::new(RwLock::new(None)),
                 tick_count: Arc::new(AtomicU64::new(0)),
+                tick_timings: Arc::new(Mutex::new(TimingLog::default())),
                 debug: Arc::new(AtomicBool::new(init_settings.debug)),
                 error: Arc::new(AtomicBool::new(false)),
+                count_in: Arc::new(AtomicBool::new(init_settings.count_in)),
+                restart: Arc::new(AtomicBool::new(false)),
+                song_map: Arc::new(RwLock::new(None)),
+                current_section: Arc::new(AtomicUsize::new(0)),
+                accents: Arc::new(AtomicU64::new(1)),
             },
             current_screen: CurrentScreen::Main,
             currently_editing: None,
@@ -83,10 +190,29 @@ impl App {
             ]),
             edit_menu: Menu::new(vec![]),
             sound_selection_menu: Menu::new(vec![]),
+            song_map_menu: Menu::new(vec![]),
+            audio_menu: Menu::new(vec![]),
+            audio_devices: Vec::new(),
             should_quit: false,
             first_edit: true,
-            sound_list: Vec::new(),
+            sound_dirs: sound_dirs(None),
+            skipped_sounds: Vec::new(),
+            added_sounds: Vec::new(),
+            sound_watcher: None,
+            sound_menu_rows: Vec::new(),
+            preview_request: None,
+            preview_output: None,
             tick_rate: set_tick_rate,
+            setlist: None,
+            song_path: None,
+            config_path: None,
+            calibration_taps: Vec::new(),
+            flash_downbeat: false,
+            shown_beat: (1, 0, Instant::now()),
+            stage_mode: false,
+            terminal_size: (80, 24),
+            click_areas: ClickAreas::default(),
+            help_scroll: None,
         }
     }
 
@@ -95,42 +221,288 @@ impl App {
             Ok(()) => {
                 self.spawn_metronome_thread();
                 self.main_menu.select(0);
+                if !self.skipped_sounds.is_empty() {
+                    self.alert_string = "Skipped ".to_owned()
+                        + &self.skipped_sounds.len().to_string()
+                        + " file(s) in the sound folders that are not sounds, see the debug panel (-d)";
+                }
+                // watch the sound folders so new sounds show up without a restart
+                match SoundWatcher::new(&self.sound_dirs) {
+                    Ok(watcher) => {
+                        if !watcher.warnings.is_empty() {
+                            self.alert_string = "Not watching some sound folders for changes: "
+                                .to_owned()
+                                + &watcher.warnings.join(", ");
+                        }
+                        self.sound_watcher = Some(watcher);
+                    }
+                    Err(error) => {
+                        self.alert_string = "Not watching the sound folders for changes: "
+                            .to_owned()
+                            + &error.to_string()
+                    }
+                }
             }
             Err(error) => {
                 println!("Problem populating sounds: {}", error);
                 self.settings.error.swap(true, Ordering::Relaxed);
             }
         };
-        let ns_delay = self.get_ns_for_note_value();
+        let ns_delay = self.settings.get_ns_for_note_value();
         self.settings.ns_delay.swap(ns_delay, Ordering::Relaxed);
-        let beats_per_bar = self.get_beats_per_bar();
+        let beats_per_bar = self.settings.get_beats_per_bar();
         self.settings
             .beats_per_bar
             .swap(beats_per_bar, Ordering::Relaxed);
     }
 
-    fn populate_sounds(&mut self) -> Result<(), Report> {
-        // loop through sounds found in /assets and add them to the sound_list vec
-        // TODO: In the future, nested sound directories could be nice to organize by type
-        if let Ok(entries) = fs::read_dir("./assets/") {
-            for entry in entries {
-                let string: String = entry?.file_name().into_string().unwrap();
-                self.sound_list.push(string);
+    pub fn populate_sounds(&mut self) -> Result<(), Report> {
+        // search the sound folders for sounds and add them to the sound_list vec, along with any added by path
+        let scan = find_sounds(&self.sound_dirs)?;
+        let mut sounds = scan.sounds;
+        for sound in &self.added_sounds {
+            insert_file(&mut sounds, sound.clone());
+        }
+        self.skipped_sounds = scan.skipped;
+
+        // keep the selected sound selected by name as the list may have changed around it. This is done while the
+        // list is locked so the metronome never sees the index point at the wrong sound
+        let mut sound_list = self.settings.sound_list.write().unwrap();
+        let selected = sound_list
+            .get(self.settings.selected_sound.load(Ordering::Relaxed))
+            .map(Sound::name);
+        let index = selected
+            .and_then(|name| sounds.iter().position(|sound| sound.name() == name))
+            .unwrap_or(0);
+        *sound_list = sounds;
+        self.settings.selected_sound.swap(index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Search the sound folders again if they have changed, and say what was added or removed
+    pub fn check_sound_folders(&mut self) {
+        let changed = match self.sound_watcher.as_mut() {
+            Some(watcher) => watcher.changed(Instant::now()),
+            None => false,
+        };
+        if changed {
+            self.reload_sounds();
+        }
+    }
+
+    pub fn reload_sounds(&mut self) {
+        let names = |app: &App| -> Vec<String> {
+            let sound_list = app.settings.sound_list.read().unwrap();
+            sound_list.iter().map(Sound::name).collect()
+        };
+        let old_names = names(self);
+        let selected = self.get_selected_sound_string();
+        if let Err(error) = self.populate_sounds() {
+            self.alert_string = "Problem reloading sounds: ".to_owned() + &error.to_string();
+            return;
+        }
+        let new_names = names(self);
+        // the list may have moved under a preview that hasn't played yet, and changed files have to be loaded again
+        self.preview_request = None;
+        self.settings.sample_cache.lock().unwrap().clear();
+
+        let mut changes = Vec::new();
+        let added: Vec<&str> = new_names
+            .iter()
+            .filter(|name| !old_names.contains(name))
+            .map(String::as_str)
+            .collect();
+        if !added.is_empty() {
+            changes.push("Added ".to_owned() + &added.join(", "));
+        }
+        let removed: Vec<&str> = old_names
+            .iter()
+            .filter(|name| !new_names.contains(name))
+            .map(String::as_str)
+            .collect();
+        if !removed.is_empty() {
+            changes.push("Removed ".to_owned() + &removed.join(", "));
+        }
+        if self.get_selected_sound_string() != selected {
+            changes.push("switched to ".to_owned() + &self.get_selected_sound_string());
+        }
+        if !changes.is_empty() {
+            self.alert_string = changes.join("; ");
+        }
+        if self.current_screen == CurrentScreen::SoundSelection && self.currently_editing.is_none()
+        {
+            self.refresh_sound_selection_menu();
+        }
+    }
+
+    // Add a sound file from anywhere on disk and select it. It is listed after the other uncategorised sound files
+    pub fn add_sound(&mut self, path: &str) -> Result<(), Report> {
+        let sound = load_sound_file(path)?;
+        self.added_sounds.push(sound.clone());
+        let mut sound_list = self.settings.sound_list.write().unwrap();
+        let index = insert_file(&mut sound_list, sound);
+        // select while the list is still locked so the metronome never sees the index point at the wrong sound
+        self.settings.selected_sound.swap(index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Add the sound file typed into the edit pop up, the alert_string explains what is wrong if it can't be used
+    pub fn add_sound_editor(&mut self) -> bool {
+        let path = self.edit_string.trim().to_string();
+        match self.add_sound(&path) {
+            Ok(_) => {
+                self.clear_strings();
+                self.currently_editing = None;
+                self.alert_string = "Added ".to_owned() + &self.get_selected_sound_string();
+                true
+            }
+            Err(error) => {
+                self.alert_string = error.to_string();
+                false
             }
         }
+    }
 
-        // clone these over to the metronome settings vec prior to spawning metronome thread
-        self.settings.sound_list = self.sound_list.clone();
+    // Ask for the highlighted sound to be previewed, replacing any preview that hasn't played yet
+    fn request_preview(&mut self) {
+        let row = self.sound_selection_menu.state.selected();
+        self.preview_request = match row.and_then(|row| self.sound_menu_rows.get(row)) {
+            Some(Some(index)) => Some((Instant::now(), *index)),
+            _ => None,
+        };
+    }
+
+    // The sound to preview if the highlight has rested on it long enough
+    pub fn take_due_preview(&mut self, now: Instant) -> Option<usize> {
+        match self.preview_request {
+            Some((requested, index)) if now.duration_since(requested) >= PREVIEW_DELAY => {
+                self.preview_request = None;
+                Some(index)
+            }
+            _ => None,
+        }
+    }
 
+    // Play the requested preview once at the current volume. It uses its own output stream so the metronome's
+    // timing isn't touched
+    pub fn play_due_preview(&mut self) {
+        let Some(index) = self.take_due_preview(Instant::now()) else {
+            return;
+        };
+        // a device that has failed (ie was unplugged) won't play anything, so open it again
+        if self
+            .preview_output
+            .as_ref()
+            .and_then(|output| output.error())
+            .is_some()
+        {
+            self.preview_output = None;
+        }
+        if self.preview_output.is_none() {
+            let output = self.get_audio_output();
+            match self.settings.open_audio_sink(&output) {
+                Ok(output) => self.preview_output = Some(output),
+                Err(error) => {
+                    self.alert_string = "Could not preview sound: ".to_owned() + &error.to_string();
+                    return;
+                }
+            }
+        }
+        let sound = self.settings.sound_list.read().unwrap()[index].clone();
+        let source = self.settings.open_sound(&sound, TickKind::Normal);
+        let gain = tick_gain(self.get_volume());
+        match (source, &self.preview_output) {
+            (Ok(source), Some(output)) => output.play(Box::new(source.amplify(gain))),
+            (Err(error), _) => self.alert_string = error.to_string(),
+            _ => {}
+        }
+    }
+
+    // Config functions
+    // Load the settings saved by an earlier run
+    pub fn load_config(&mut self) -> Result<(), Report> {
+        let Some(path) = &self.config_path else {
+            return Ok(());
+        };
+        let config = Config::load(path)?;
+        *self.settings.sound_processing.write().unwrap() = config.sounds;
+        self.settings
+            .latency_offset
+            .swap(config.latency_offset, Ordering::Relaxed);
+        self.flash_downbeat = config.flash_downbeat;
+        self.set_audio_output(config.audio);
         Ok(())
     }
 
+    // Save the settings for the next run, any problem is shown in the notification area
+    pub fn save_config(&mut self) {
+        let Some(path) = &self.config_path else {
+            return;
+        };
+        let config = Config {
+            latency_offset: self.settings.latency_offset.load(Ordering::Relaxed),
+            flash_downbeat: self.flash_downbeat,
+            audio: self.settings.audio_output.read().unwrap().clone(),
+            sounds: self.settings.sound_processing.read().unwrap().clone(),
+        };
+        if let Err(error) = config.save(path) {
+            self.alert_string = "Could not save settings: ".to_owned() + &error.to_string();
+        }
+    }
+
+    // Audio output functions
+    // Switch the click to a different device. The metronome opens it straight away and reports back through
+    // audio_error if it can't
+    pub fn set_audio_output(&mut self, output: AudioOutput) {
+        *self.settings.audio_output.write().unwrap() = output;
+        *self.settings.audio_error.write().unwrap() = None;
+        self.settings.reopen_output.swap(true, Ordering::Relaxed);
+        // previews are played on the chosen device too
+        self.preview_output = None;
+    }
+
+    // Choose a device from the audio menu and remember it for next time
+    pub fn choose_audio_output(&mut self, output: AudioOutput) {
+        self.set_audio_output(output);
+        self.save_config();
+        self.refresh_audio_menu();
+    }
+
+    // Step through the default host and each host on the system, the device goes back to the host's default
+    pub fn cycle_audio_host(&mut self) {
+        let mut hosts = vec![None];
+        hosts.extend(audio::host_names().into_iter().map(Some));
+        let current = self.get_audio_output().host;
+        let index = hosts.iter().position(|host| *host == current).unwrap_or(0);
+        let host = hosts[(index + 1) % hosts.len()].clone();
+        self.choose_audio_output(AudioOutput { host, device: None });
+    }
+
+    // Try the chosen device again, ie after plugging it back in
+    pub fn retry_audio_output(&mut self) {
+        let output = self.get_audio_output();
+        self.set_audio_output(output);
+    }
+
+    // Select a sound by its name in the sound list
+    pub fn select_sound(&mut self, name: &str) -> Result<(), Report> {
+        let sound_list = self.settings.sound_list.read().unwrap();
+        match sound_list.iter().position(|sound| sound.name() == name) {
+            Some(index) => {
+                self.settings.selected_sound.swap(index, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(eyre!("Could not find the sound {}", name)),
+        }
+    }
+
     // Spawns a metronome on its own thread
     fn spawn_metronome_thread(&mut self) {
-        let mut metronome = Metronome::new(&self.settings);
+        let settings = self.settings.clone();
         let tick_rate_copy = self.tick_rate;
+        // The metronome is made on its thread as the audio device it opens can't be moved between threads
         self.metronome_handle = Some(thread::spawn(move || {
-            metronome.start(tick_rate_copy);
+            Metronome::new(&settings).start(tick_rate_copy);
         }));
         self.check_error_status();
     }
@@ -151,10 +523,48 @@ impl App {
         note + "/" + &value
     }
     pub fn get_bar_count_string(&mut self) -> String {
-        self.settings.bar_count.load(Ordering::Relaxed).to_string()
+        self.settings
+            .visual_bar_count
+            .load(Ordering::Relaxed)
+            .to_string()
     }
     pub fn get_selected_sound_string(&mut self) -> String {
-        self.sound_list[self.settings.selected_sound.load(Ordering::Relaxed)].to_string()
+        self.settings.sound_list.read().unwrap()
+            [self.settings.selected_sound.load(Ordering::Relaxed)]
+        .label()
+    }
+    pub fn get_synth_pitch(&mut self) -> f64 {
+        self.settings.synth_pitch.load(Ordering::Relaxed)
+    }
+    pub fn get_synth_decay(&mut self) -> f64 {
+        self.settings.synth_decay.load(Ordering::Relaxed)
+    }
+    pub fn get_sample_processing(&mut self) -> SoundProcessing {
+        let name = self.get_selected_sound_name();
+        self.settings.get_sound_processing(&name)
+    }
+    fn get_selected_sound_name(&mut self) -> String {
+        self.settings.sound_list.read().unwrap()
+            [self.settings.selected_sound.load(Ordering::Relaxed)]
+        .name()
+    }
+    pub fn get_audio_output(&mut self) -> AudioOutput {
+        self.settings.audio_output.read().unwrap().clone()
+    }
+    pub fn get_audio_error(&mut self) -> Option<String> {
+        self.settings.audio_error.read().unwrap().clone()
+    }
+    pub fn get_audio_warning(&mut self) -> Option<String> {
+        self.settings.audio_warning.read().unwrap().clone()
+    }
+    pub fn get_count_in(&mut self) -> bool {
+        self.settings.count_in.load(Ordering::Relaxed)
+    }
+    // Title and position of the current song, ie "Song Title (3/12)"
+    pub fn get_setlist_string(&mut self) -> Option<String> {
+        self.setlist
+            .as_ref()
+            .map(|setlist| setlist.current().name.clone() + " (" + &setlist.position_string() + ")")
     }
 
     // Metronome settings change functions
@@ -163,7 +573,7 @@ impl App {
             return;
         }
         self.settings.bpm.swap(new_bpm, Ordering::Relaxed);
-        let new_ns = self.get_ns_for_note_value();
+        let new_ns = self.settings.get_ns_for_note_value();
         self.settings.ns_delay.swap(new_ns, Ordering::Relaxed);
     }
 
@@ -191,7 +601,7 @@ impl App {
             };
             if self.verify_bpm(new_bpm) {
                 self.settings.bpm.swap(new_bpm, Ordering::Relaxed);
-                let new_ns_delay = self.get_ns_for_note_value();
+                let new_ns_delay = self.settings.get_ns_for_note_value();
                 self.settings.ns_delay.swap(new_ns_delay, Ordering::Relaxed);
                 self.clear_strings();
                 self.currently_editing = None;
@@ -223,6 +633,155 @@ impl App {
         }
     }
 
+    pub fn change_synth_pitch_editor(&mut self) -> bool {
+        match self.edit_string.parse::<f64>() {
+            Ok(new_pitch) if PITCH_RANGE.contains(&new_pitch) => {
+                self.settings.synth_pitch.swap(new_pitch, Ordering::Relaxed);
+                self.clear_strings();
+                self.currently_editing = None;
+                true
+            }
+            _ => {
+                self.edit_string.clear();
+                false
+            }
+        }
+    }
+
+    pub fn change_synth_decay_editor(&mut self) -> bool {
+        match self.edit_string.parse::<f64>() {
+            Ok(new_decay) if DECAY_RANGE.contains(&new_decay) => {
+                self.settings.synth_decay.swap(new_decay, Ordering::Relaxed);
+                self.clear_strings();
+                self.currently_editing = None;
+                true
+            }
+            _ => {
+                self.edit_string.clear();
+                false
+            }
+        }
+    }
+
+    pub fn change_sample_pitch_editor(&mut self) -> bool {
+        match self.edit_string.parse::<f64>() {
+            Ok(new_pitch) if PITCH_RANGE.contains(&new_pitch) => {
+                self.change_sample_processing(|processing| processing.pitch = new_pitch);
+                self.clear_strings();
+                self.currently_editing = None;
+                true
+            }
+            _ => {
+                self.edit_string.clear();
+                false
+            }
+        }
+    }
+
+    // Latency functions
+    pub fn get_latency_offset(&self) -> i64 {
+        self.settings.latency_offset.load(Ordering::Relaxed)
+    }
+
+    pub fn change_latency_offset_editor(&mut self) -> bool {
+        match self.edit_string.parse::<i64>() {
+            Ok(new_offset) if LATENCY_RANGE.contains(&new_offset) => {
+                self.settings
+                    .latency_offset
+                    .swap(new_offset, Ordering::Relaxed);
+                self.clear_strings();
+                self.currently_editing = None;
+                self.save_config();
+                true
+            }
+            _ => {
+                self.edit_string.clear();
+                false
+            }
+        }
+    }
+
+    // Open the calibration screen with the click playing, so there is something to tap along to
+    pub fn start_calibration(&mut self) {
+        self.calibration_taps.clear();
+        if !self.get_is_running() {
+            self.toggle_metronome();
+        }
+        self.switch_screen(CurrentScreen::Calibration);
+    }
+
+    // Measure how far a tap was from the nearest click. A negative offset holds the click back, so that is taken
+    // into account to measure from when the click actually went out
+    pub fn calibration_tap(&mut self, time: Instant) {
+        let Some(last_tick) = *self.settings.last_tick_time.read().unwrap() else {
+            return;
+        };
+        let audio_delay = Duration::from_millis(self.get_latency_offset().min(0).unsigned_abs());
+        let delay = Duration::from_nanos(self.settings.ns_delay.load(Ordering::Relaxed));
+        let click = last_tick + audio_delay;
+        let offset_ms = |click: Instant| match time >= click {
+            true => time.duration_since(click).as_secs_f64() * 1000.0,
+            false => -(click.duration_since(time).as_secs_f64() * 1000.0),
+        };
+        // the tap could be for the last click, the one before it (while the click is held back) or the next one
+        let nearest = [click.checked_sub(delay), Some(click), Some(click + delay)]
+            .into_iter()
+            .flatten()
+            .map(offset_ms)
+            .min_by(|a, b| a.abs().total_cmp(&b.abs()));
+        if let Some(offset) = nearest {
+            self.calibration_taps.push(offset);
+        }
+    }
+
+    // The average of the taps as a latency offset, once there have been enough of them
+    pub fn get_calibration_offset(&self) -> Option<i64> {
+        if self.calibration_taps.len() < MIN_CALIBRATION_TAPS {
+            return None;
+        }
+        let mean = self.calibration_taps.iter().sum::<f64>() / self.calibration_taps.len() as f64;
+        Some((mean.round() as i64).clamp(*LATENCY_RANGE.start(), *LATENCY_RANGE.end()))
+    }
+
+    // Use the suggested offset and go back to the edit menu
+    pub fn apply_calibration(&mut self) {
+        let Some(offset) = self.get_calibration_offset() else {
+            self.alert_string = "Tap along with at least ".to_owned()
+                + &MIN_CALIBRATION_TAPS.to_string()
+                + " clicks first";
+            return;
+        };
+        self.settings.latency_offset.swap(offset, Ordering::Relaxed);
+        self.save_config();
+        self.switch_screen(CurrentScreen::Editing);
+    }
+
+    pub fn toggle_sample_trim(&mut self) {
+        self.change_sample_processing(|processing| processing.trim = !processing.trim);
+    }
+
+    pub fn cycle_sample_normalize(&mut self) {
+        self.change_sample_processing(|processing| {
+            processing.normalize = processing.normalize.next()
+        });
+    }
+
+    // Change the processing of the selected sound and save it. The sample is loaded again with the new processing
+    // the next time it plays
+    fn change_sample_processing(&mut self, change: impl FnOnce(&mut SoundProcessing)) {
+        let name = self.get_selected_sound_name();
+        let mut processing = self.get_sample_processing();
+        change(&mut processing);
+        let mut sound_processing = self.settings.sound_processing.write().unwrap();
+        if processing == SoundProcessing::default() {
+            sound_processing.remove(&name);
+        } else {
+            sound_processing.insert(name, processing);
+        }
+        drop(sound_processing);
+        self.save_config();
+    }
+
     pub fn toggle_metronome(&mut self) {
         let currently_playing = self.settings.is_running.load(Ordering::Relaxed);
         self.settings
@@ -232,57 +791,255 @@ impl App {
         self.check_error_status();
     }
 
-    // Convert a bpm value to the nanosecond delay (1/4 notes)
-    fn get_ns_from_bpm(&mut self) -> u64 {
-        (60_000_000_000.0_f64 / self.settings.bpm.load(Ordering::Relaxed) as f64).round() as u64
+    pub fn toggle_count_in(&mut self) {
+        let count_in = self.get_count_in();
+        self.settings.count_in.swap(!count_in, Ordering::Relaxed);
     }
 
-    // Take the current nanosecond delay and divide it based on the value note in the time signature
-    fn get_ns_for_note_value(&mut self) -> u64 {
-        let value = self.settings.ts_value.load(Ordering::Relaxed);
-        let mut current_ns_delay = self.get_ns_from_bpm(); // length of a quarter note
+    pub fn get_muted(&self) -> bool {
+        self.settings.muted.load(Ordering::Relaxed)
+    }
 
-        // Handle triplet meters like 12/8
-        if value == 8 {
-            current_ns_delay = (current_ns_delay as f64 / 3_f64).round() as u64;
-        } else if value != 4 {
-            current_ns_delay = match value {
-                64 => (current_ns_delay as f64 / 16_f64).round() as u64,
-                32 => (current_ns_delay as f64 / 8_f64).round() as u64,
-                16 => (current_ns_delay as f64 / 4_f64).round() as u64,
-                8 => (current_ns_delay as f64 / 2_f64).round() as u64,
-                _ => current_ns_delay,
-            }
+    // Silence the click while it keeps counting, unlike stopping the beat indicator carries on
+    pub fn toggle_mute(&mut self) {
+        let muted = self.get_muted();
+        self.settings.muted.swap(!muted, Ordering::Relaxed);
+    }
+
+    pub fn toggle_stage_mode(&mut self) {
+        self.stage_mode = !self.stage_mode;
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.terminal_size = (width, height);
+    }
+
+    // The lines stage mode shows: the tempo, the time signature and the bar.beat being shown ("-" while stopped)
+    pub fn get_stage_lines(&mut self) -> [String; 3] {
+        let indicator = self.get_beat_indicator();
+        let position = match indicator.current {
+            Some(beat) => self.get_bar_count_string() + "." + &(beat + 1).to_string(),
+            None => "-.-".to_string(),
+        };
+        [
+            self.get_bpm().to_string() + " BPM",
+            self.get_time_sig_string(),
+            position,
+        ]
+    }
+
+    // Accent or un-accent a beat of the bar, beat 0 being the downbeat
+    pub fn toggle_accent(&mut self, beat: u64) {
+        if beat < 64 {
+            self.settings
+                .accents
+                .fetch_xor(1 << beat, Ordering::Relaxed);
+        }
+    }
+
+    pub fn toggle_flash_downbeat(&mut self) {
+        self.flash_downbeat = !self.flash_downbeat;
+        self.save_config();
+    }
+
+    // Work out which beat of the bar is being shown from the shown tick count, so subdivisions light up the beat they
+    // belong to. The flash is timed from when the downbeat was first shown
+    pub fn get_beat_indicator(&mut self) -> BeatIndicator {
+        let beats = self.settings.ts_note.load(Ordering::Relaxed).max(1);
+        let beats_per_bar = self.settings.beats_per_bar.load(Ordering::Relaxed).max(1);
+        let bar = self.settings.visual_bar_count.load(Ordering::Relaxed);
+        let beat_count = self.settings.visual_beat_count.load(Ordering::Relaxed);
+        if (bar, beat_count) != (self.shown_beat.0, self.shown_beat.1) {
+            self.shown_beat = (bar, beat_count, Instant::now());
+        }
+        // the beat count is 1 based once a tick has played, and the time signature can change under it mid bar
+        let current = beat_count
+            .checked_sub(1)
+            .map(|tick| (tick * beats / beats_per_bar).min(beats - 1));
+        let flash =
+            self.flash_downbeat && beat_count == 1 && self.shown_beat.2.elapsed() < FLASH_LENGTH;
+        BeatIndicator {
+            beats,
+            current,
+            accents: self.settings.accents.load(Ordering::Relaxed),
+            flash,
         }
-        // Calculate 8ths or 16ths subdivision in 4/4
-        if value == 4 {
-            if self.settings.sub_eights.load(Ordering::Relaxed) {
-                current_ns_delay = (current_ns_delay as f64 / 2_f64).round() as u64;
-            } else if self.settings.sub_sixteens.load(Ordering::Relaxed) {
-                current_ns_delay = (current_ns_delay as f64 / 4_f64).round() as u64;
+    }
+
+    // Setlist functions
+    pub fn load_setlist(&mut self, setlist: Setlist) {
+        let preset = setlist.current().clone();
+        self.setlist = Some(setlist);
+        self.apply_preset(&preset);
+    }
+
+    pub fn setlist_next(&mut self) {
+        if let Some(preset) = self.setlist.as_mut().and_then(|s| s.next().cloned()) {
+            self.jump_to_preset(&preset);
+        }
+    }
+
+    pub fn setlist_previous(&mut self) {
+        if let Some(preset) = self.setlist.as_mut().and_then(|s| s.previous().cloned()) {
+            self.jump_to_preset(&preset);
+        }
+    }
+
+    // Switch to a new song instantly, restarting from the top of the bar. With count-in enabled this also starts the
+    // metronome so the band gets a bar to come in on
+    fn jump_to_preset(&mut self, preset: &Preset) {
+        self.apply_preset(preset);
+        if self.get_count_in() {
+            self.settings.is_running.swap(true, Ordering::Relaxed);
+        }
+        self.settings.restart.swap(true, Ordering::Relaxed);
+    }
+
+    pub fn apply_preset(&mut self, preset: &Preset) {
+        if self.verify_bpm(preset.bpm) {
+            self.settings
+                .set_tempo_and_meter(preset.bpm as f64, preset.ts_note, preset.ts_value);
+        }
+    }
+
+    // Song map functions
+    // Load a song map file, any problems with the file are shown in the notification area
+    pub fn load_song_file(&mut self, path: &str) {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str());
+        if matches!(extension, Some("mid" | "midi" | "MID" | "MIDI")) {
+            self.import_midi_file(path);
+            return;
+        }
+        self.song_path = Some(path.to_string());
+        match SongMap::load(path) {
+            Ok(song_map) => self.load_song_map(song_map),
+            Err(error) => self.alert_string = error.to_string(),
+        }
+    }
+
+    // Import the tempo and meter changes of a MIDI file as a song map. Edits are saved next to it as a text song map
+    pub fn import_midi_file(&mut self, path: &str) {
+        let text_path = Path::new(path).with_extension("txt");
+        self.song_path = Some(text_path.to_string_lossy().to_string());
+        match midi::import_song_map(path) {
+            Ok(import) => {
+                let mut alert = "Imported ".to_owned()
+                    + &import.song_map.sections.len().to_string()
+                    + " sections from "
+                    + path;
+                if !import.warnings.is_empty() {
+                    alert += " (warning: ";
+                    alert += &import.warnings.join("; ");
+                    alert += ")";
+                }
+                self.load_song_map(import.song_map);
+                self.alert_string = alert;
             }
-            // This was helpful in thinking about triplet calculation:
-            // https://math.stackexchange.com/questions/2646908/calculating-delay-time-in-milliseconds
-            if self.settings.ts_triplets.load(Ordering::Relaxed) {
-                current_ns_delay = (current_ns_delay as f64 / 3_f64 * 2_f64).round() as u64;
+            Err(error) => self.alert_string = error.to_string(),
+        }
+    }
+
+    // Save the song map back to the file it was loaded from (or song_map.txt if it was made from scratch)
+    pub fn save_song_file(&mut self) {
+        let path = self
+            .song_path
+            .clone()
+            .unwrap_or_else(|| "song_map.txt".to_string());
+        let song_map = self.settings.song_map.read().unwrap().clone();
+        self.alert_string = match song_map.unwrap_or_default().save(&path) {
+            Ok(_) => {
+                self.song_path = Some(path.clone());
+                "Saved song map to ".to_owned() + &path
             }
+            Err(error) => error.to_string(),
+        };
+    }
+
+    // Loading a song map restarts the metronome from the first bar of the song
+    pub fn load_song_map(&mut self, song_map: SongMap) {
+        if let Some(section) = song_map.sections.first() {
+            self.settings
+                .set_tempo_and_meter(section.bpm, section.ts_note, section.ts_value);
         }
+        *self.settings.song_map.write().unwrap() = Some(song_map);
+        self.settings.current_section.swap(0, Ordering::Relaxed);
+        self.settings.restart.swap(true, Ordering::Relaxed);
+    }
 
-        current_ns_delay
+    // Replace, add or (when the edit_string is empty) remove the song map section being edited
+    pub fn change_section_editor(&mut self, index: usize) -> bool {
+        let mut song_map = self.settings.song_map.write().unwrap();
+        let mut sections = song_map.take().unwrap_or_default().sections;
+        let result = if self.edit_string.trim().is_empty() {
+            if index < sections.len() {
+                sections.remove(index);
+            }
+            true
+        } else {
+            match parse_section(&self.edit_string) {
+                Ok(section) if index < sections.len() => {
+                    sections[index] = section;
+                    true
+                }
+                Ok(section) => {
+                    sections.push(section);
+                    true
+                }
+                Err((column, message)) => {
+                    self.alert_string =
+                        "column ".to_owned() + &column.to_string() + ": " + &message;
+                    false
+                }
+            }
+        };
+        if !sections.is_empty() {
+            *song_map = Some(SongMap { sections });
+        }
+        drop(song_map);
+        if result {
+            self.clear_strings();
+            self.currently_editing = None;
+        }
+        result
     }
 
-    // Calculate and return the number of metronome beats per bar (based on time signature and subdivision)
-    fn get_beats_per_bar(&mut self) -> u64 {
-        let mut num_ticks = self.settings.ts_note.load(Ordering::Relaxed);
-        if self.settings.ts_triplets.load(Ordering::Relaxed) {
-            num_ticks = (num_ticks as f64 * 1.5_f64).round() as u64;
+    // The song map file line for a section, used as the starting value when editing it
+    pub fn get_section_line(&mut self, index: usize) -> String {
+        match self.settings.song_map.read().unwrap().as_ref() {
+            Some(map) if index < map.sections.len() => map.sections[index].to_line(),
+            _ => String::new(),
         }
-        if self.settings.sub_eights.load(Ordering::Relaxed) {
-            num_ticks *= 2;
-        } else if self.settings.sub_sixteens.load(Ordering::Relaxed) {
-            num_ticks *= 4;
+    }
+
+    // How late the ticks have played compared to when they were due, ie "min 0.012 max 0.950 mean 0.081 stddev
+    // 0.064 ms (120 ticks)"
+    pub fn get_jitter_string(&self) -> String {
+        match self.settings.tick_timings.lock().unwrap().stats() {
+            Some(stats) => format!(
+                "min {:.3} max {:.3} mean {:.3} stddev {:.3} ms ({} ticks)",
+                stats.min,
+                stats.max,
+                stats.mean,
+                stats.stddev(),
+                stats.count
+            ),
+            None => "no ticks yet".to_string(),
         }
-        num_ticks
+    }
+
+    // Name and position of the section currently playing, ie "Verse (1/4)"
+    pub fn get_section_string(&mut self) -> Option<String> {
+        let song_map = self.settings.song_map.read().unwrap();
+        let map = song_map.as_ref()?;
+        let index = self.settings.current_section.load(Ordering::Relaxed);
+        map.sections.get(index).map(|section| {
+            section.name.clone()
+                + " ("
+                + &(index + 1).to_string()
+                + "/"
+                + &map.sections.len().to_string()
+                + ")"
+        })
     }
 
     pub fn clear_strings(&mut self) {
@@ -294,11 +1051,27 @@ impl App {
         if self.settings.error.load(Ordering::Relaxed) {
             self.current_screen = CurrentScreen::Error;
         }
+        // a missing audio device can be fixed without quitting, so it gets its own screen
+        let audio_error = self.settings.audio_error.read().unwrap().is_some();
+        if audio_error
+            && !matches!(
+                self.current_screen,
+                CurrentScreen::AudioError
+                    | CurrentScreen::AudioSettings
+                    | CurrentScreen::Exiting
+                    | CurrentScreen::Error
+            )
+        {
+            self.switch_screen(CurrentScreen::AudioError);
+        }
     }
 
+    // The edit menu, its items are in the order of the *_ITEM constants
     pub fn refresh_edit_menu(&mut self) {
         let edit_menu_selection = self.edit_menu.state.selected();
         let is_playing = if self.get_is_running() { "yes" } else { "no" };
+        let is_muted = if self.get_muted() { "yes" } else { "no" };
+        let processing = self.get_sample_processing();
         let mut edit_menu_vec = vec![
             "playing: ".to_owned() + is_playing,
             "bpm: ".to_owned() + &self.get_bpm().to_string(),
@@ -306,6 +1079,17 @@ impl App {
             "select sound: ".to_owned() + &self.get_selected_sound_string(),
             "Time signature: ".to_owned() + &self.get_time_sig_string(),
             "Bar count: ".to_owned() + &self.get_bar_count_string(),
+            "song map: ".to_owned() + self.song_path.as_deref().unwrap_or("none"),
+            "synth pitch: ".to_owned() + &self.get_synth_pitch().to_string() + " semitones",
+            "synth decay: ".to_owned() + &self.get_synth_decay().to_string() + " ms",
+            "trim silence: ".to_owned() + if processing.trim { "yes" } else { "no" },
+            "normalize: ".to_owned() + processing.normalize.name(),
+            "sample pitch: ".to_owned() + &processing.pitch.to_string() + " semitones",
+            "audio output: ".to_owned() + &self.get_audio_output().label(),
+            "latency offset: ".to_owned() + &self.get_latency_offset().to_string() + " ms",
+            "Calibrate latency".to_owned(),
+            "flash on beat 1: ".to_owned() + if self.flash_downbeat { "yes" } else { "no" },
+            "muted: ".to_owned() + is_muted,
             "Back to main menu".to_owned(),
         ];
         // Add debug displays
@@ -319,225 +1103,550 @@ impl App {
                 "Current NS Delay: ".to_owned()
                     + &self.settings.ns_delay.load(Ordering::Relaxed).to_string(),
             );
+            edit_menu_vec.push("Jitter: ".to_owned() + &self.get_jitter_string());
+            for reason in &self.skipped_sounds {
+                edit_menu_vec.push("Skipped: ".to_owned() + reason);
+            }
         }
         self.edit_menu.set_items(edit_menu_vec);
 
-        // clippy hates this no matter what I do...
-        if let Some(..) = edit_menu_selection {
-            self.edit_menu.select(edit_menu_selection.unwrap());
+        if let Some(selection) = edit_menu_selection {
+            self.edit_menu.select(selection);
         }
     }
 
     pub fn refresh_sound_selection_menu(&mut self) {
-        // list sounds
-        self.sound_selection_menu.set_items(self.sound_list.clone());
+        // list sounds as a tree, each group gets a heading (a pack is its own heading) with its sounds under it
+        let sound_list = self.settings.sound_list.read().unwrap();
+        let mut items = Vec::new();
+        self.sound_menu_rows.clear();
+        let mut last_group = None;
+        let mut pack = None;
+        for (index, sound) in sound_list.iter().enumerate() {
+            let group = sound.group();
+            if group != last_group {
+                pack = None;
+                if let (Some(name), false) = (&group, matches!(sound, Sound::Pack(_))) {
+                    items.push("▾ ".to_owned() + name);
+                    self.sound_menu_rows.push(None);
+                }
+                last_group = group.clone();
+            }
+            let item = match sound {
+                Sound::Pack(sound_pack) => {
+                    pack = Some(sound_pack);
+                    "▾ ".to_owned() + &sound_pack.category + " (pack)"
+                }
+                _ if group.is_some() => {
+                    let mut item = "    ".to_owned() + &sound.short_name();
+                    // show which clicks the pack plays the file on
+                    if let (Some(pack), Sound::File { path, .. }) = (pack, sound) {
+                        let roles: Vec<&str> = [
+                            (TickKind::Accent, "accent"),
+                            (TickKind::Normal, "normal"),
+                            (TickKind::Subdivision, "subdivision"),
+                        ]
+                        .into_iter()
+                        .filter(|(kind, _)| pack.role_path(*kind) == path)
+                        .map(|(_, role)| role)
+                        .collect();
+                        if !roles.is_empty() {
+                            item += &(" [".to_owned() + &roles.join(", ") + "]");
+                        }
+                    }
+                    item
+                }
+                _ => sound.label(),
+            };
+            items.push(item);
+            self.sound_menu_rows.push(Some(index));
+        }
+        drop(sound_list);
+        items.push("Add sound from file...".to_owned());
+        self.sound_selection_menu.set_items(items);
         // select the current sound
-        self.sound_selection_menu
-            .select(self.settings.selected_sound.load(Ordering::Relaxed));
+        let selected_sound = self.settings.selected_sound.load(Ordering::Relaxed);
+        let row = self
+            .sound_menu_rows
+            .iter()
+            .position(|row| *row == Some(selected_sound));
+        self.sound_selection_menu.select(row.unwrap_or(0));
     }
 
-    // TODO: Separate ui nav code from app -----------------------------------------------------------------------------
-    pub fn update(&mut self, key: KeyEvent) -> Result<String, Report> {
-        let mut ask_for_quit = false; // used to prevent pressing q to quit entire program with no warning
+    pub fn refresh_song_map_menu(&mut self) {
+        let selection = self.song_map_menu.state.selected();
+        let mut items: Vec<String> = match self.settings.song_map.read().unwrap().as_ref() {
+            Some(map) => map.sections.iter().map(|s| s.to_line()).collect(),
+            None => Vec::new(),
+        };
+        items.push("Add section".to_owned());
+        items.push("Save song map".to_owned());
+        items.push("Back to edit menu".to_owned());
+        self.song_map_menu.set_items(items);
+        self.song_map_menu.select(selection.unwrap_or(0));
+    }
 
+    pub fn refresh_audio_menu(&mut self) {
+        // the host is listed first, then the default device and each device of the host, then back
+        let selection = self.audio_menu.state.selected();
+        let output = self.get_audio_output();
+        let mut items =
+            vec!["Host: ".to_owned() + output.host.as_deref().unwrap_or("default host")];
+        self.audio_devices = match audio::device_names(output.host.as_deref()) {
+            Ok(devices) => devices,
+            Err(error) => {
+                self.alert_string = error.to_string();
+                Vec::new()
+            }
+        };
+        let mark = |chosen: bool| if chosen { "* " } else { "  " };
+        items.push(mark(output.device.is_none()).to_owned() + "Default device");
+        for device in &self.audio_devices {
+            items.push(mark(output.device.as_ref() == Some(device)).to_owned() + device);
+        }
+        items.push("Back to edit menu".to_owned());
+        self.audio_menu.set_items(items);
+        self.audio_menu.select(selection.unwrap_or(0));
+    }
+
+    // Whether the user is typing free text (rather than a number), in which case letter shortcuts are disabled
+    pub fn is_text_input(&self) -> bool {
+        matches!(
+            self.currently_editing,
+            Some(CurrentlyEditing::Section(_) | CurrentlyEditing::SoundPath)
+        )
+    }
+
+    // Add / remove characters from the edit_string while editing a value
+    fn edit_string_input(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Char(value) => {
+                if self.first_edit {
+                    self.edit_string.clear();
+                    self.first_edit = false;
+                }
+                self.edit_string.push(value);
+            }
+            KeyCode::Backspace => {
+                self.edit_string.pop();
+            }
+            _ => {}
+        }
+    }
+
+    // Keyboard controls, each key press runs the action of its binding in the keymap for the current screen
+    pub fn update(&mut self, key: KeyEvent) -> Result<String, Report> {
         // If in error mode, return error
         if self.settings.error.load(Ordering::Relaxed) {
             return Err(eyre!("App.update() Something went wrong!"));
         }
-        // global keyboard shortcuts and menu navigation controls
-        match key.code {
-            // navigate menu items
-            KeyCode::Up
-            | KeyCode::Left
-            | KeyCode::BackTab
-            | KeyCode::Down
-            | KeyCode::Right
-            | KeyCode::Tab
-            | KeyCode::Esc => {
-                self.menu_navigate(key);
-            }
-            KeyCode::Char('+') => {
+        // the help overlay takes every key press while it is open
+        let bindings = match self.help_scroll {
+            Some(_) => keymap::HELP_BINDINGS,
+            None => keymap::BINDINGS,
+        };
+        let binding = keymap::find(
+            bindings,
+            &self.current_screen,
+            self.currently_editing.is_some(),
+            self.is_text_input(),
+            key.code,
+        );
+        if let Some(binding) = binding {
+            self.run_action(binding.action, key)?;
+        }
+
+        Ok("App updated".to_string())
+    }
+
+    fn run_action(&mut self, action: Action, key: KeyEvent) -> Result<(), Report> {
+        match action {
+            Action::Previous | Action::Next | Action::Back => self.menu_navigate(action),
+            Action::Select => self.select(),
+            Action::Save => self.save_edit(),
+            Action::Type => self.edit_string_input(key),
+            Action::BpmUp => {
                 let old_bpm = self.get_bpm();
                 self.change_bpm(old_bpm + 10);
             }
-            KeyCode::Char('-') => {
-                let old_bpm = self.get_bpm();
-                self.change_bpm(old_bpm - 10);
+            Action::BpmDown => {
+                let old_bpm = self.get_bpm();
+                self.change_bpm(old_bpm.saturating_sub(10));
+            }
+            Action::ToggleMetronome => self.toggle_metronome(),
+            Action::PreviousSong => self.setlist_previous(),
+            Action::NextSong => self.setlist_next(),
+            Action::ToggleCountIn => self.toggle_count_in(),
+            Action::ToggleStageMode => self.toggle_stage_mode(),
+            Action::ToggleMute => self.toggle_mute(),
+            Action::AskToQuit => {
+                self.current_screen = CurrentScreen::Exiting;
+                self.edit_menu.deselect();
+                self.currently_editing = None;
+                self.clear_strings();
+            }
+            Action::Quit => self.should_quit = true,
+            Action::StayOpen => {
+                // Reset the menu state to a default value
+                self.current_screen = CurrentScreen::Main;
+                self.currently_editing = None;
+                self.clear_strings();
+                self.first_edit = true;
+                self.main_menu.select(0);
+            }
+            Action::ChooseOutput => self.switch_screen(CurrentScreen::AudioSettings),
+            Action::RetryOutput => {
+                self.retry_audio_output();
+                self.switch_screen(CurrentScreen::Main);
+            }
+            Action::Tap => self.calibration_tap(Instant::now()),
+            Action::ApplyCalibration => self.apply_calibration(),
+            Action::ReportError => {
+                return Err(eyre!(
+                    "ReadyMetronome experienced a terminal error! Sorry about that..."
+                ));
+            }
+            Action::OpenHelp => self.help_scroll = Some(0),
+            Action::DismissNotification => self.alert_string.clear(),
+            Action::ScrollHelpUp => {
+                self.help_scroll = self.help_scroll.map(|scroll| scroll.saturating_sub(1))
+            }
+            // ui.rs keeps the scroll from going past the last line
+            Action::ScrollHelpDown => self.help_scroll = self.help_scroll.map(|scroll| scroll + 1),
+            Action::CloseHelp => self.help_scroll = None,
+        }
+        Ok(())
+    }
+
+    // Choose the highlighted item of the current screen's menu
+    fn select(&mut self) {
+        match self.current_screen {
+            // Main screen -----------------------------------------------------------------------------------------
+            CurrentScreen::Main => {
+                let current_selection = self.main_menu.state.selected().unwrap();
+                // TODO: This is messy and bad, magic numbers are not scalable
+                match current_selection {
+                    0 => {
+                        // start / stop metronome
+                        self.toggle_metronome();
+                    }
+                    1 => {
+                        // enter edit menu
+                        self.switch_screen(CurrentScreen::Editing);
+                    }
+                    2 => {
+                        // enter quit menu
+                        self.current_screen = CurrentScreen::Exiting;
+                    }
+                    _ => {}
+                }
+            }
+            // Edit screen -----------------------------------------------------------------------------------------
+            CurrentScreen::Editing => {
+                let current_selection = self.edit_menu.state.selected().unwrap();
+                match current_selection {
+                    PLAYING_ITEM => {
+                        // start / stop metronome
+                        self.toggle_metronome()
+                    }
+                    BPM_ITEM => {
+                        // edit bpm
+                        self.edit_string = self.get_bpm().to_string();
+                        self.currently_editing = Some(CurrentlyEditing::Bpm);
+                        self.edit_menu.deselect();
+                    }
+                    VOLUME_ITEM => {
+                        // edit volume
+                        self.edit_string = self.get_volume().to_string();
+                        self.currently_editing = Some(CurrentlyEditing::Volume);
+                        self.edit_menu.deselect();
+                    }
+                    SOUND_ITEM => {
+                        // sound selection menu
+                        self.switch_screen(CurrentScreen::SoundSelection);
+                    }
+                    TIME_SIGNATURE_ITEM => {
+                        // edit time signature
+                        // TODO: Add the editing functionality for this :)
+                    }
+                    BAR_COUNT_ITEM => {
+                        // bar count display, do nothing
+                    }
+                    SONG_MAP_ITEM => {
+                        // song map screen
+                        self.switch_screen(CurrentScreen::SongMap);
+                    }
+                    SYNTH_PITCH_ITEM => {
+                        // edit synth pitch
+                        self.edit_string = self.get_synth_pitch().to_string();
+                        self.currently_editing = Some(CurrentlyEditing::SynthPitch);
+                        self.edit_menu.deselect();
+                    }
+                    SYNTH_DECAY_ITEM => {
+                        // edit synth decay
+                        self.edit_string = self.get_synth_decay().to_string();
+                        self.currently_editing = Some(CurrentlyEditing::SynthDecay);
+                        self.edit_menu.deselect();
+                    }
+                    TRIM_ITEM => {
+                        // toggle trimming the selected sample's leading silence
+                        self.toggle_sample_trim();
+                    }
+                    NORMALIZE_ITEM => {
+                        // step through the normalize modes of the selected sample
+                        self.cycle_sample_normalize();
+                    }
+                    SAMPLE_PITCH_ITEM => {
+                        // edit the selected sample's pitch
+                        self.edit_string = self.get_sample_processing().pitch.to_string();
+                        self.currently_editing = Some(CurrentlyEditing::SamplePitch);
+                        self.edit_menu.deselect();
+                    }
+                    AUDIO_OUTPUT_ITEM => {
+                        // audio output screen
+                        self.switch_screen(CurrentScreen::AudioSettings);
+                    }
+                    LATENCY_OFFSET_ITEM => {
+                        // edit latency offset
+                        self.edit_string = self.get_latency_offset().to_string();
+                        self.currently_editing = Some(CurrentlyEditing::LatencyOffset);
+                        self.edit_menu.deselect();
+                    }
+                    CALIBRATION_ITEM => {
+                        // latency calibration screen
+                        self.start_calibration();
+                    }
+                    FLASH_ITEM => {
+                        // toggle flashing the beat indicator on beat 1
+                        self.toggle_flash_downbeat();
+                    }
+                    MUTED_ITEM => {
+                        // mute / unmute the click
+                        self.toggle_mute();
+                    }
+                    BACK_ITEM => {
+                        // back to main menu
+                        self.switch_screen(CurrentScreen::Main);
+                    }
+                    _ => {}
+                }
+            }
+            // Sound Selection Screen ------------------------------------------------------------------------------
+            CurrentScreen::SoundSelection => {
+                // the sound tree is listed first, followed by add sound from file. Group headings can't be
+                // selected unless they are a pack
+                let selection = self.sound_selection_menu.state.selected().unwrap();
+                match self.sound_menu_rows.get(selection) {
+                    Some(Some(index)) => {
+                        self.settings.selected_sound.swap(*index, Ordering::Relaxed);
+                        self.switch_screen(CurrentScreen::Editing);
+                    }
+                    Some(None) => {}
+                    None => {
+                        self.currently_editing = Some(CurrentlyEditing::SoundPath);
+                        self.first_edit = false;
+                    }
+                }
             }
-            // toggle metronome on/off
-            KeyCode::Char('t') => {
-                if self.currently_editing.is_none() {
-                    self.toggle_metronome();
+            // Song Map Screen -------------------------------------------------------------------------------------
+            CurrentScreen::SongMap => {
+                // the sections are listed first, followed by add, save and back
+                let selection = self.song_map_menu.state.selected().unwrap();
+                let num_sections = self.song_map_menu.items.len() - 3;
+                if selection <= num_sections {
+                    // edit a section (or add a new one), keeping the original text to edit in place
+                    self.edit_string = self.get_section_line(selection);
+                    self.currently_editing = Some(CurrentlyEditing::Section(selection));
+                    self.first_edit = false;
+                } else if selection == num_sections + 1 {
+                    self.save_song_file();
+                } else {
+                    self.switch_screen(CurrentScreen::Editing);
                 }
             }
-            // quit at any time
-            KeyCode::Char('q') => {
-                if self.current_screen != CurrentScreen::Exiting {
-                    self.current_screen = CurrentScreen::Exiting;
-                    self.edit_menu.deselect();
-                    self.currently_editing = None;
-                    self.clear_strings();
-                    ask_for_quit = true;
+            // Audio Settings Screen -------------------------------------------------------------------------------
+            CurrentScreen::AudioSettings => {
+                // the host is listed first, followed by the default device, the host's devices and back
+                let selection = self.audio_menu.state.selected().unwrap();
+                let host = self.get_audio_output().host;
+                if selection == 0 {
+                    self.cycle_audio_host();
+                } else if selection == 1 {
+                    self.choose_audio_output(AudioOutput { host, device: None });
+                } else if selection < self.audio_devices.len() + 2 {
+                    let device = Some(self.audio_devices[selection - 2].clone());
+                    self.choose_audio_output(AudioOutput { host, device });
+                } else {
+                    self.switch_screen(CurrentScreen::Editing);
                 }
             }
             _ => {}
         }
+    }
 
-        // Screen specific keyboard shortcuts
-        // Main screen ---------------------------------------------------------------------------------------------
-        match self.current_screen {
-            CurrentScreen::Main => {
-                if key.code == KeyCode::Enter {
-                    let current_selection = self.main_menu.state.selected().unwrap();
-                    // TODO: This is messy and bad, magic numbers are not scalable
-                    match current_selection {
-                        0 => {
-                            // start / stop metronome
-                            self.toggle_metronome();
-                        }
-                        1 => {
-                            // enter edit menu
-                            self.switch_screen(CurrentScreen::Editing);
-                        }
-                        2 => {
-                            // enter quit menu
-                            self.current_screen = CurrentScreen::Exiting;
-                        }
-                        _ => {}
-                    }
+    // Save the value being edited, or retry if it can't be used
+    fn save_edit(&mut self) {
+        let Some(editing) = self.currently_editing else {
+            return;
+        };
+        match editing {
+            CurrentlyEditing::Bpm => {
+                if self.change_bpm_editor() {
+                    self.edit_menu.select(BPM_ITEM);
+                    self.first_edit = true;
+                } else {
+                    self.alert_string = "Please input a value between 20 and 500".to_owned();
                 }
             }
-            // Edit screen -----------------------------------------------------------------------------------------
-            CurrentScreen::Editing => match key.code {
-                // When editing a value, add / remove characters from the edit_string
-                KeyCode::Char(value) => {
-                    if self.currently_editing.is_some() {
-                        if self.first_edit {
-                            self.edit_string.clear();
-                            self.first_edit = false;
-                        }
-                        self.edit_string.push(value);
-                    }
-                }
-                KeyCode::Backspace => {
-                    if self.currently_editing.is_some() {
-                        self.edit_string.pop();
-                    }
-                }
-                // When editing a value, save the result or retry if failed
-                KeyCode::Enter => {
-                    if let Some(editing) = &self.currently_editing {
-                        match editing {
-                            CurrentlyEditing::Bpm => {
-                                if self.change_bpm_editor() {
-                                    self.edit_menu.select(1);
-                                    self.first_edit = true;
-                                } else {
-                                    self.alert_string =
-                                        "Please input a value between 20 and 500".to_owned();
-                                }
-                            }
-                            CurrentlyEditing::Volume => {
-                                if self.change_volume_editor() {
-                                    self.edit_menu.select(2);
-                                    self.first_edit = true;
-                                } else {
-                                    self.alert_string =
-                                        "Please input a value between 1.0 and 200.0".to_owned();
-                                }
-                            }
-                        }
-                    } else {
-                        // Main edit menu --------------------------------------------
-                        // TODO: This is messy and bad, magic numbers are not scalable
-                        let current_selection = self.edit_menu.state.selected().unwrap();
-                        match current_selection {
-                            0 => {
-                                // start / stop metronome
-                                self.toggle_metronome()
-                            }
-                            1 => {
-                                // edit bpm
-                                self.edit_string = self.get_bpm().to_string();
-                                self.currently_editing = Some(CurrentlyEditing::Bpm);
-                                self.edit_menu.deselect();
-                            }
-                            2 => {
-                                // edit volume
-                                self.edit_string = self.get_volume().to_string();
-                                self.currently_editing = Some(CurrentlyEditing::Volume);
-                                self.edit_menu.deselect();
-                            }
-                            3 => {
-                                // sound selection menu
-                                self.switch_screen(CurrentScreen::SoundSelection);
-                            }
-                            4 => {
-                                // edit time signature
-                                // TODO: Add the editing functionality for this :)
-                            }
-                            5 => {
-                                // bar count display, do nothing
-                            }
-                            6 => {
-                                // back to main menu
-                                self.switch_screen(CurrentScreen::Main);
-                            }
-                            _ => {}
-                        }
-                    }
+            CurrentlyEditing::Volume => {
+                if self.change_volume_editor() {
+                    self.edit_menu.select(VOLUME_ITEM);
+                    self.first_edit = true;
+                } else {
+                    self.alert_string = "Please input a value between 1.0 and 200.0".to_owned();
                 }
-                _ => {}
-            },
-            // Sound Selection Screen ------------------------------------------------------------------------------
-            CurrentScreen::SoundSelection => {
-                if key.code == KeyCode::Enter {
-                    let selection = self.sound_selection_menu.state.selected().unwrap();
-                    if selection <= self.sound_list.len() {
-                        self.settings
-                            .selected_sound
-                            .swap(selection, Ordering::Relaxed);
-                    }
-                    self.switch_screen(CurrentScreen::Editing);
+            }
+            CurrentlyEditing::SynthPitch => {
+                if self.change_synth_pitch_editor() {
+                    self.edit_menu.select(SYNTH_PITCH_ITEM);
+                    self.first_edit = true;
+                } else {
+                    self.alert_string = "Please input a value between -24 and 24".to_owned();
                 }
             }
-            // Exit screen -----------------------------------------------------------------------------------------
-            CurrentScreen::Exiting => match key.code {
-                KeyCode::Char('y') | KeyCode::Char('q') | KeyCode::Enter => {
-                    // Quit
-                    if !ask_for_quit {
-                        self.should_quit = true;
-                    }
+            CurrentlyEditing::SynthDecay => {
+                if self.change_synth_decay_editor() {
+                    self.edit_menu.select(SYNTH_DECAY_ITEM);
+                    self.first_edit = true;
+                } else {
+                    self.alert_string = "Please input a value between 5 and 1000".to_owned();
                 }
-                KeyCode::Char('n') | KeyCode::Backspace | KeyCode::Esc | KeyCode::Tab => {
-                    // Reset the menu state to a default value
-                    self.current_screen = CurrentScreen::Main;
-                    self.currently_editing = None;
-                    self.clear_strings();
+            }
+            CurrentlyEditing::SamplePitch => {
+                if self.change_sample_pitch_editor() {
+                    self.edit_menu.select(SAMPLE_PITCH_ITEM);
                     self.first_edit = true;
-                    self.main_menu.select(0);
+                } else {
+                    self.alert_string = "Please input a value between -24 and 24".to_owned();
                 }
-                _ => {}
-            },
-            // Error screen ----------------------------------------------------------------------------------------
-            CurrentScreen::Error => {
-                // Press any char to quit, could not find an "any" keybind in Crossterm
-                if let KeyCode::Char(_) = key.code {
-                    return Err(eyre!(
-                        "ReadyMetronome experienced a terminal error! Sorry about that..."
-                    ));
+            }
+            CurrentlyEditing::LatencyOffset => {
+                if self.change_latency_offset_editor() {
+                    self.edit_menu.select(LATENCY_OFFSET_ITEM);
+                    self.first_edit = true;
+                } else {
+                    self.alert_string =
+                        "Please input a whole number of ms between -500 and 500".to_owned();
+                }
+            }
+            // add the sound file, the alert_string explains what is wrong if it can't be used
+            CurrentlyEditing::SoundPath => {
+                if self.add_sound_editor() {
+                    self.refresh_sound_selection_menu();
+                }
+            }
+            // save the edited section, the alert_string explains what is wrong if it doesn't parse
+            CurrentlyEditing::Section(index) => {
+                if self.change_section_editor(index) {
+                    self.first_edit = true;
+                    self.refresh_song_map_menu();
                 }
             }
         }
+    }
 
+    // Mouse controls: clicking a menu item selects and activates it like (enter), scrolling over the bpm in the status
+    // list changes the tempo one bpm at a time, and clicking a beat of the beat indicator toggles its accent
+    pub fn mouse(&mut self, event: MouseEvent) -> Result<String, Report> {
+        // the help overlay covers whatever screen it is opened on, the wheel scrolls it
+        if let Some(scroll) = self.help_scroll {
+            match event.kind {
+                MouseEventKind::ScrollUp => self.help_scroll = Some(scroll.saturating_sub(1)),
+                MouseEventKind::ScrollDown => self.help_scroll = Some(scroll + 1),
+                _ => {}
+            }
+            return Ok("App updated".to_string());
+        }
+        // the pop ups and the screens drawn over the menus take no clicks
+        if self.currently_editing.is_some()
+            || !matches!(
+                self.current_screen,
+                CurrentScreen::Main
+                    | CurrentScreen::Editing
+                    | CurrentScreen::SoundSelection
+                    | CurrentScreen::SongMap
+                    | CurrentScreen::AudioSettings
+            )
+        {
+            return Ok("App updated".to_string());
+        }
+        let (column, row) = (event.column, event.row);
+        let right_panel = self.click_areas.right_panel;
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                let beat = self
+                    .click_areas
+                    .beat_cells
+                    .iter()
+                    .position(|cell| cell.contains(Position::new(column, row)));
+                if let Some(beat) = beat {
+                    self.toggle_accent(beat as u64);
+                } else if let Some(index) =
+                    self.main_menu
+                        .item_at(self.click_areas.main_menu, column, row)
+                {
+                    if self.current_screen != CurrentScreen::Main {
+                        self.switch_screen(CurrentScreen::Main);
+                    }
+                    self.main_menu.select(index);
+                    return self.update(KeyEvent::from(KeyCode::Enter));
+                } else if let Some(index) =
+                    self.right_panel_menu().item_at(right_panel, column, row)
+                {
+                    // the status list is shown on the main screen too, clicking it goes into the edit menu
+                    if self.current_screen == CurrentScreen::Main {
+                        self.switch_screen(CurrentScreen::Editing);
+                    }
+                    self.right_panel_menu().select(index);
+                    return self.update(KeyEvent::from(KeyCode::Enter));
+                }
+            }
+            MouseEventKind::ScrollUp | MouseEventKind::ScrollDown
+                if matches!(
+                    self.current_screen,
+                    CurrentScreen::Main | CurrentScreen::Editing
+                ) && self.edit_menu.item_at(right_panel, column, row) == Some(BPM_ITEM) =>
+            {
+                let old_bpm = self.get_bpm();
+                match event.kind {
+                    MouseEventKind::ScrollUp => self.change_bpm(old_bpm + 1),
+                    _ => self.change_bpm(old_bpm.saturating_sub(1)),
+                }
+            }
+            _ => {}
+        }
         Ok("App updated".to_string())
     }
 
+    // The menu shown in the right panel of the current screen
+    fn right_panel_menu(&mut self) -> &mut Menu {
+        match self.current_screen {
+            CurrentScreen::SoundSelection => &mut self.sound_selection_menu,
+            CurrentScreen::SongMap => &mut self.song_map_menu,
+            CurrentScreen::AudioSettings => &mut self.audio_menu,
+            _ => &mut self.edit_menu,
+        }
+    }
+
     fn switch_screen(&mut self, new_screen: CurrentScreen) {
+        // a preview only makes sense while still browsing the sounds
+        self.preview_request = None;
         match new_screen {
             CurrentScreen::Main => {
                 self.edit_menu.deselect();
                 self.sound_selection_menu.deselect();
+                self.song_map_menu.deselect();
+                self.audio_menu.deselect();
                 self.first_edit = true;
                 if self.current_screen == CurrentScreen::Editing {
                     self.main_menu.select(1);
@@ -548,17 +1657,50 @@ impl App {
             CurrentScreen::Editing => {
                 self.main_menu.deselect();
                 self.sound_selection_menu.deselect();
-                self.edit_menu.select(0);
+                self.song_map_menu.deselect();
+                self.audio_menu.deselect();
+                self.currently_editing = None;
+                if self.current_screen == CurrentScreen::SongMap {
+                    self.edit_menu.select(SONG_MAP_ITEM);
+                } else if self.current_screen == CurrentScreen::AudioSettings {
+                    self.edit_menu.select(AUDIO_OUTPUT_ITEM);
+                } else if self.current_screen == CurrentScreen::Calibration {
+                    self.edit_menu.select(CALIBRATION_ITEM);
+                } else {
+                    self.edit_menu.select(PLAYING_ITEM);
+                }
             }
             CurrentScreen::SoundSelection => {
                 self.main_menu.deselect();
                 self.edit_menu.deselect();
                 self.refresh_sound_selection_menu();
             }
+            CurrentScreen::SongMap => {
+                self.main_menu.deselect();
+                self.edit_menu.deselect();
+                self.song_map_menu.select(0);
+                self.refresh_song_map_menu();
+            }
+            CurrentScreen::AudioSettings => {
+                self.main_menu.deselect();
+                self.edit_menu.deselect();
+                self.audio_menu.select(0);
+                self.refresh_audio_menu();
+            }
+            CurrentScreen::AudioError | CurrentScreen::Calibration => {
+                self.main_menu.deselect();
+                self.edit_menu.deselect();
+                self.sound_selection_menu.deselect();
+                self.song_map_menu.deselect();
+                self.audio_menu.deselect();
+                self.currently_editing = None;
+            }
             CurrentScreen::Exiting => {
                 self.main_menu.deselect();
                 self.edit_menu.deselect();
                 self.sound_selection_menu.deselect();
+                self.song_map_menu.deselect();
+                self.audio_menu.deselect();
                 self.currently_editing = None;
                 self.clear_strings();
             }
@@ -567,14 +1709,16 @@ impl App {
                 self.main_menu.deselect();
                 self.edit_menu.deselect();
                 self.sound_selection_menu.deselect();
+                self.song_map_menu.deselect();
+                self.audio_menu.deselect();
             }
         }
         self.current_screen = new_screen;
     }
 
-    fn menu_navigate(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Up | KeyCode::Left | KeyCode::BackTab => match self.current_screen {
+    fn menu_navigate(&mut self, action: Action) {
+        match action {
+            Action::Previous => match self.current_screen {
                 CurrentScreen::Main => {
                     self.main_menu.previous();
                 }
@@ -584,12 +1728,24 @@ impl App {
                     }
                 }
                 CurrentScreen::SoundSelection => {
-                    self.sound_selection_menu.previous();
+                    if self.currently_editing.is_none() {
+                        self.sound_selection_menu.previous();
+                        self.request_preview();
+                    }
+                }
+                CurrentScreen::SongMap => {
+                    if self.currently_editing.is_none() {
+                        self.song_map_menu.previous();
+                    }
                 }
+                CurrentScreen::AudioSettings => {
+                    self.audio_menu.previous();
+                }
+                CurrentScreen::AudioError | CurrentScreen::Calibration => {}
                 CurrentScreen::Exiting => {}
                 CurrentScreen::Error => {}
             },
-            KeyCode::Down | KeyCode::Right | KeyCode::Tab => match self.current_screen {
+            Action::Next => match self.current_screen {
                 CurrentScreen::Main => {
                     self.main_menu.next();
                 }
@@ -599,18 +1755,30 @@ impl App {
                     }
                 }
                 CurrentScreen::SoundSelection => {
-                    self.sound_selection_menu.next();
+                    if self.currently_editing.is_none() {
+                        self.sound_selection_menu.next();
+                        self.request_preview();
+                    }
+                }
+                CurrentScreen::SongMap => {
+                    if self.currently_editing.is_none() {
+                        self.song_map_menu.next();
+                    }
                 }
+                CurrentScreen::AudioSettings => {
+                    self.audio_menu.next();
+                }
+                CurrentScreen::AudioError | CurrentScreen::Calibration => {}
                 CurrentScreen::Exiting => {}
                 CurrentScreen::Error => {}
             },
-            KeyCode::Esc => {
+            Action::Back => {
                 match self.current_screen {
                     CurrentScreen::Main => {}
                     CurrentScreen::Editing => {
                         // if in EditMode return to EditScreen, if in EditScreen return to MainScreen
                         if self.currently_editing.is_some() {
-                            self.edit_menu.select(0);
+                            self.edit_menu.select(PLAYING_ITEM);
                             self.currently_editing = None;
                             self.clear_strings();
                         } else {
@@ -620,8 +1788,29 @@ impl App {
                         }
                     }
                     CurrentScreen::SoundSelection => {
+                        // if typing a file path discard it, otherwise return to the edit menu
+                        if self.currently_editing.is_some() {
+                            self.currently_editing = None;
+                            self.clear_strings();
+                            self.first_edit = true;
+                        } else {
+                            self.switch_screen(CurrentScreen::Editing);
+                        }
+                    }
+                    CurrentScreen::SongMap => {
+                        // if editing a section discard the changes, otherwise return to the edit menu
+                        if self.currently_editing.is_some() {
+                            self.currently_editing = None;
+                            self.clear_strings();
+                            self.first_edit = true;
+                        } else {
+                            self.switch_screen(CurrentScreen::Editing);
+                        }
+                    }
+                    CurrentScreen::AudioSettings | CurrentScreen::Calibration => {
                         self.switch_screen(CurrentScreen::Editing);
                     }
+                    CurrentScreen::AudioError => {}
                     CurrentScreen::Exiting => {}
                     CurrentScreen::Error => {}
                 }
@@ -633,8 +1822,11 @@ impl App {
 
 // Tests ---------------------------------------------------------------------------------------------------------------
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crossterm::event::KeyModifiers;
+    use std::fs;
 
     const TEST_SETTINGS: InitMetronomeSettings = InitMetronomeSettings {
         bpm: 120,
@@ -643,6 +1835,12 @@ mod tests {
         volume: 100.0,
         is_running: false,
         debug: false,
+        count_in: false,
+        ts_triplets: false,
+        sub_eights: false,
+        sub_sixteens: false,
+        synth_pitch: 0.0,
+        synth_decay: 40.0,
     };
 
     const TEST_TICK_RATE: u64 = 7;
@@ -749,6 +1947,513 @@ mod tests {
         assert_eq!(test_app.get_volume(), 100.0);
     }
 
+    // the built in sounds should be selectable by name even without an assets folder
+    #[test]
+    fn app_select_synth_sound() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs.clear();
+        test_app.populate_sounds().unwrap();
+        test_app.select_sound("FM cowbell").unwrap();
+        assert_eq!(test_app.get_selected_sound_string(), "FM cowbell (synth)");
+        test_app.select_sound("EmeryBoardClick.wav").unwrap();
+        assert!(test_app
+            .settings
+            .open_selected_sound(TickKind::Normal)
+            .is_ok());
+        assert!(test_app.select_sound("Not a sound").is_err());
+    }
+
+    #[test]
+    fn app_change_synth_pitch_editor() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.edit_string = "-12".to_string();
+        assert!(test_app.change_synth_pitch_editor());
+        assert_eq!(test_app.get_synth_pitch(), -12.0);
+        test_app.edit_string = "25".to_string();
+        assert!(!test_app.change_synth_pitch_editor());
+        assert_eq!(test_app.get_synth_pitch(), -12.0);
+    }
+
+    // typing a negative pitch should go into the editor rather than turn the tempo down
+    #[test]
+    fn app_type_synth_pitch() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.edit_menu.select(SYNTH_PITCH_ITEM);
+        test_app.current_screen = CurrentScreen::Editing;
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert!(matches!(
+            test_app.currently_editing,
+            Some(CurrentlyEditing::SynthPitch)
+        ));
+        test_app.update(KeyEvent::from(KeyCode::Backspace)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Char('-'))).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Char('5'))).unwrap();
+        assert_eq!(test_app.edit_string, "-5");
+        assert_eq!(test_app.get_bpm(), 120);
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert_eq!(test_app.get_synth_pitch(), -5.0);
+        assert_eq!(test_app.get_bpm(), 120);
+    }
+
+    // changing a sample's processing should reload it processed and be saved for the next run
+    #[test]
+    fn app_sample_processing() {
+        let path = std::env::temp_dir().join("readymetronome_app_config/config.toml");
+        let _ = fs::remove_file(&path);
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs.clear();
+        test_app.config_path = Some(path.clone());
+        test_app.populate_sounds().unwrap();
+        test_app.select_sound("EmeryBoardClick.wav").unwrap();
+        let original = test_app
+            .settings
+            .open_selected_sound(TickKind::Normal)
+            .unwrap()
+            .count();
+
+        test_app.toggle_sample_trim();
+        test_app.cycle_sample_normalize();
+        test_app.edit_string = "12".to_string();
+        assert!(test_app.change_sample_pitch_editor());
+        let processed = test_app
+            .settings
+            .open_selected_sound(TickKind::Normal)
+            .unwrap()
+            .count();
+        assert!(processed < original / 2 + 2);
+
+        let mut next_run = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        next_run.sound_dirs.clear();
+        next_run.config_path = Some(path.clone());
+        next_run.populate_sounds().unwrap();
+        next_run.load_config().unwrap();
+        next_run.select_sound("EmeryBoardClick.wav").unwrap();
+        let processing = next_run.get_sample_processing();
+        assert!(processing.trim);
+        assert_eq!(processing.normalize, crate::processing::Normalize::Peak);
+        assert_eq!(processing.pitch, 12.0);
+
+        // other sounds are left as they are
+        next_run.select_sound("TronicClick1.wav").unwrap();
+        assert_eq!(next_run.get_sample_processing(), SoundProcessing::default());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    // a sound file added by path should be listed after the other sound files and selected
+    #[test]
+    fn app_add_sound() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs.clear();
+        test_app.populate_sounds().unwrap();
+        test_app.edit_string = "./assets/TronicClick1.wav".to_string();
+        assert!(test_app.add_sound_editor());
+        assert_eq!(test_app.settings.selected_sound.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            test_app.get_selected_sound_string(),
+            "TronicClick1.wav (./assets)"
+        );
+
+        test_app.edit_string = "./README.md".to_string();
+        assert!(!test_app.add_sound_editor());
+        assert!(test_app.alert_string.contains("not a WAV"));
+        assert_eq!(test_app.settings.selected_sound.load(Ordering::Relaxed), 0);
+    }
+
+    // sound categories should be listed as a tree, and a pack heading should select the pack
+    #[test]
+    fn app_refresh_sound_selection_menu() {
+        let dir = std::env::temp_dir().join("readymetronome_app_sound_tree");
+        fs::create_dir_all(dir.join("Woodblocks")).unwrap();
+        fs::copy("./assets/TronicClick1.wav", dir.join("Woodblocks/high.wav")).unwrap();
+        fs::copy(
+            "./assets/EmeryBoardClick.wav",
+            dir.join("Woodblocks/low.wav"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Woodblocks/pack.toml"),
+            "accent = \"high.wav\"\nnormal = \"low.wav\"",
+        )
+        .unwrap();
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs = vec![dir.clone()];
+        test_app.populate_sounds().unwrap();
+        test_app.refresh_sound_selection_menu();
+
+        let items = &test_app.sound_selection_menu.items;
+        assert_eq!(items[0], "▾ Woodblocks (pack)");
+        assert_eq!(items[1], "    high.wav [accent]");
+        assert_eq!(items[2], "    low.wav [normal, subdivision]");
+        assert_eq!(items[3], "▾ Built in");
+        assert_eq!(test_app.sound_menu_rows[3], None);
+        assert_eq!(test_app.sound_menu_rows[4], Some(3));
+
+        test_app.switch_screen(CurrentScreen::SoundSelection);
+        test_app.sound_selection_menu.select(0);
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert_eq!(
+            test_app.get_selected_sound_string(),
+            "Woodblocks (".to_owned() + &dir.display().to_string() + " pack)"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // moving the highlight should only preview the sound it rests on, and headings have nothing to preview
+    #[test]
+    fn app_preview_sound() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs.clear();
+        test_app.populate_sounds().unwrap();
+        test_app.switch_screen(CurrentScreen::SoundSelection);
+        assert!(test_app.preview_request.is_none());
+
+        // the highlight starts on EmeryBoardClick.wav under the "Built in" heading
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        let (requested, index) = test_app.preview_request.unwrap();
+        assert_eq!(index, 1);
+        assert!(test_app.take_due_preview(requested).is_none());
+        assert_eq!(
+            test_app.take_due_preview(requested + PREVIEW_DELAY),
+            Some(1)
+        );
+        assert!(test_app
+            .take_due_preview(requested + PREVIEW_DELAY)
+            .is_none());
+
+        // on to the "Synth" heading
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        assert!(test_app.preview_request.is_none());
+    }
+
+    // reloading the sounds should keep the selection by name and say what changed
+    #[test]
+    fn app_reload_sounds() {
+        let dir = std::env::temp_dir().join("readymetronome_app_reload_sounds");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("./assets/TronicClick1.wav", dir.join("b.wav")).unwrap();
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.sound_dirs = vec![dir.clone()];
+        test_app.populate_sounds().unwrap();
+        test_app.select_sound("EmeryBoardClick.wav").unwrap();
+
+        fs::copy("./assets/TronicClick1.wav", dir.join("a.wav")).unwrap();
+        test_app.reload_sounds();
+        assert_eq!(test_app.alert_string, "Added a.wav");
+        assert_eq!(
+            test_app.get_selected_sound_string(),
+            "EmeryBoardClick.wav (built in)"
+        );
+
+        test_app.select_sound("b.wav").unwrap();
+        fs::remove_file(dir.join("b.wav")).unwrap();
+        test_app.reload_sounds();
+        assert!(test_app
+            .alert_string
+            .starts_with("Removed b.wav; switched to a.wav"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // the beat indicator should light up the beat each shown tick belongs to, and only flash on the downbeat
+    #[test]
+    fn app_beat_indicator() {
+        let settings = InitMetronomeSettings {
+            sub_eights: true,
+            ..TEST_SETTINGS
+        };
+        let mut test_app = App::new(settings, TEST_TICK_RATE);
+        test_app.settings.set_tempo_and_meter(120.0, 4, 4);
+        test_app.settings.accents.swap(0b101, Ordering::Relaxed);
+        let indicator = test_app.get_beat_indicator();
+        assert_eq!(indicator.beats, 4);
+        assert_eq!(indicator.current, None);
+        assert_eq!(indicator.accents, 0b101);
+
+        let mut current = Vec::new();
+        for beat_count in 1..=8 {
+            test_app
+                .settings
+                .visual_beat_count
+                .swap(beat_count, Ordering::Relaxed);
+            current.push(test_app.get_beat_indicator().current.unwrap());
+        }
+        assert_eq!(current, vec![0, 0, 1, 1, 2, 2, 3, 3]);
+
+        test_app
+            .settings
+            .visual_beat_count
+            .swap(1, Ordering::Relaxed);
+        assert!(!test_app.get_beat_indicator().flash);
+        test_app.toggle_flash_downbeat();
+        assert!(test_app.get_beat_indicator().flash);
+        test_app.shown_beat.2 -= FLASH_LENGTH;
+        assert!(!test_app.get_beat_indicator().flash);
+    }
+
+    // clicks should select and activate menu items and toggle accents, and scrolling over the bpm should change it
+    #[test]
+    fn app_mouse() {
+        let click = |column, row| MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column,
+            row,
+            modifiers: KeyModifiers::NONE,
+        };
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.populate_sounds().unwrap();
+        test_app.refresh_edit_menu();
+        test_app.click_areas = ClickAreas {
+            main_menu: Rect::new(0, 8, 20, 10),
+            right_panel: Rect::new(20, 8, 60, 30),
+            beat_cells: (0..4).map(|beat| Rect::new(beat * 20, 3, 20, 3)).collect(),
+        };
+
+        // the first row of a menu is inside its border
+        test_app.mouse(click(5, 10)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Editing);
+        test_app.mouse(click(30, 9 + FLASH_ITEM as u16)).unwrap();
+        assert!(test_app.flash_downbeat);
+        assert_eq!(test_app.edit_menu.state.selected(), Some(FLASH_ITEM));
+        test_app.mouse(click(30, 9 + SOUND_ITEM as u16)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::SoundSelection);
+        test_app
+            .mouse(click(30, 9 + test_app.sound_menu_rows.len() as u16))
+            .unwrap();
+        assert!(matches!(
+            test_app.currently_editing,
+            Some(CurrentlyEditing::SoundPath)
+        ));
+        // clicks are ignored while typing in a value
+        test_app.mouse(click(5, 9)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::SoundSelection);
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Main);
+
+        let scroll = |kind, row| MouseEvent {
+            kind,
+            column: 30,
+            row,
+            modifiers: KeyModifiers::NONE,
+        };
+        let bpm = test_app.get_bpm();
+        test_app
+            .mouse(scroll(MouseEventKind::ScrollUp, 10))
+            .unwrap();
+        test_app
+            .mouse(scroll(MouseEventKind::ScrollUp, 10))
+            .unwrap();
+        test_app
+            .mouse(scroll(MouseEventKind::ScrollDown, 10))
+            .unwrap();
+        assert_eq!(test_app.get_bpm(), bpm + 1);
+        test_app
+            .mouse(scroll(MouseEventKind::ScrollUp, 11))
+            .unwrap();
+        assert_eq!(test_app.get_bpm(), bpm + 1);
+
+        test_app.mouse(click(45, 4)).unwrap();
+        assert_eq!(test_app.settings.accents.load(Ordering::Relaxed), 0b101);
+        test_app.mouse(click(5, 4)).unwrap();
+        assert_eq!(test_app.settings.accents.load(Ordering::Relaxed), 0b100);
+    }
+
+    // ? should open the help overlay, which takes every key until it is closed
+    #[test]
+    fn app_help_overlay() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.main_menu.select(0);
+        test_app.update(KeyEvent::from(KeyCode::Char('?'))).unwrap();
+        assert_eq!(test_app.help_scroll, Some(0));
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Up)).unwrap();
+        assert_eq!(test_app.help_scroll, Some(1));
+        // the wheel scrolls it on screens that take no clicks too
+        test_app.current_screen = CurrentScreen::Calibration;
+        let wheel = |kind| MouseEvent {
+            kind,
+            column: 0,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        };
+        test_app.mouse(wheel(MouseEventKind::ScrollDown)).unwrap();
+        assert_eq!(test_app.help_scroll, Some(2));
+        test_app.mouse(wheel(MouseEventKind::ScrollUp)).unwrap();
+        assert_eq!(test_app.help_scroll, Some(1));
+        test_app.current_screen = CurrentScreen::Main;
+        // the menu and shortcuts don't see the keys
+        test_app.update(KeyEvent::from(KeyCode::Char('t'))).unwrap();
+        assert!(!test_app.get_is_running());
+        assert_eq!(test_app.main_menu.state.selected(), Some(0));
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        assert_eq!(test_app.help_scroll, None);
+
+        // while typing a file path ? is part of it
+        test_app.current_screen = CurrentScreen::SoundSelection;
+        test_app.currently_editing = Some(CurrentlyEditing::SoundPath);
+        test_app.update(KeyEvent::from(KeyCode::Char('?'))).unwrap();
+        assert_eq!(test_app.help_scroll, None);
+        assert_eq!(test_app.edit_string, "?");
+    }
+
+    // app::toggle_mute should mute the click without stopping the metronome
+    #[test]
+    fn app_toggle_mute() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.populate_sounds().unwrap();
+        test_app.toggle_metronome();
+        test_app.update(KeyEvent::from(KeyCode::Char('m'))).unwrap();
+        assert!(test_app.get_muted());
+        assert!(test_app.get_is_running());
+        test_app.refresh_edit_menu();
+        assert_eq!(test_app.edit_menu.items[MUTED_ITEM], "muted: yes");
+        test_app.update(KeyEvent::from(KeyCode::Char('m'))).unwrap();
+        assert!(!test_app.get_muted());
+    }
+
+    // stage mode should toggle with a key and show the tempo, time signature and the bar.beat being shown
+    #[test]
+    fn app_stage_mode() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.update(KeyEvent::from(KeyCode::Char('s'))).unwrap();
+        assert!(test_app.stage_mode);
+        assert_eq!(test_app.get_stage_lines(), ["120 BPM", "4/4", "-.-"]);
+        test_app
+            .settings
+            .visual_bar_count
+            .swap(12, Ordering::Relaxed);
+        test_app
+            .settings
+            .visual_beat_count
+            .swap(3, Ordering::Relaxed);
+        assert_eq!(test_app.get_stage_lines()[2], "12.3");
+        test_app.resize(200, 60);
+        assert_eq!(test_app.terminal_size, (200, 60));
+        test_app.update(KeyEvent::from(KeyCode::Char('s'))).unwrap();
+        assert!(!test_app.stage_mode);
+    }
+
+    // tapping along should suggest the average of how late the taps were, measured from the nearest click
+    #[test]
+    fn app_latency_calibration() {
+        let path = std::env::temp_dir().join("readymetronome_app_latency/config.toml");
+        let _ = fs::remove_file(&path);
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.config_path = Some(path.clone());
+        test_app.edit_menu.select(CALIBRATION_ITEM);
+        test_app.current_screen = CurrentScreen::Editing;
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Calibration);
+        assert!(test_app.get_is_running());
+
+        // no taps count until a click has played
+        test_app.calibration_tap(Instant::now());
+        assert!(test_app.calibration_taps.is_empty());
+
+        let click = Instant::now();
+        *test_app.settings.last_tick_time.write().unwrap() = Some(click);
+        for late_ms in [30, 50, 40, 40, 35, 45] {
+            test_app.calibration_tap(click + Duration::from_millis(late_ms));
+        }
+        assert!(test_app.get_calibration_offset().is_none());
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert!(!test_app.alert_string.is_empty());
+        // a tap just before the next click (500ms later) counts as early for that click
+        test_app.calibration_tap(click + Duration::from_millis(490));
+        test_app.calibration_tap(click + Duration::from_millis(60));
+        assert!((test_app.calibration_taps[6] + 10.0).abs() < 0.001);
+        assert_eq!(test_app.get_calibration_offset(), Some(36));
+
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Editing);
+        assert_eq!(test_app.edit_menu.state.selected(), Some(CALIBRATION_ITEM));
+        assert_eq!(test_app.get_latency_offset(), 36);
+        assert_eq!(Config::load(&path).unwrap().latency_offset, 36);
+
+        // the offset can also be typed in, negative offsets hold back the click instead
+        test_app.edit_string = "-600".to_string();
+        assert!(!test_app.change_latency_offset_editor());
+        test_app.edit_string = "-20".to_string();
+        assert!(test_app.change_latency_offset_editor());
+        assert_eq!(test_app.get_latency_offset(), -20);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    // a missing audio device should show the audio error screen, and choosing a device should be remembered
+    #[test]
+    fn app_audio_output() {
+        let path = std::env::temp_dir().join("readymetronome_app_audio/config.toml");
+        let _ = fs::remove_file(&path);
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.config_path = Some(path.clone());
+        test_app.set_audio_output(AudioOutput {
+            host: None,
+            device: Some("USB Audio".to_string()),
+        });
+        *test_app.settings.audio_error.write().unwrap() =
+            Some("Could not find the audio device USB Audio".to_string());
+        test_app.check_error_status();
+        assert!(test_app.current_screen == CurrentScreen::AudioError);
+
+        // trying again waits for the metronome to open the device
+        test_app.update(KeyEvent::from(KeyCode::Char('r'))).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Main);
+        assert!(test_app.get_audio_error().is_none());
+        assert!(test_app.settings.reopen_output.load(Ordering::Relaxed));
+
+        // choosing the default device from the audio menu saves it
+        *test_app.settings.audio_error.write().unwrap() = Some("still missing".to_string());
+        test_app.check_error_status();
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::AudioSettings);
+        assert_eq!(
+            test_app.audio_menu.items.len(),
+            test_app.audio_devices.len() + 3
+        );
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Enter)).unwrap();
+        assert_eq!(test_app.get_audio_output(), AudioOutput::default());
+        assert_eq!(test_app.audio_menu.items[1], "* Default device");
+        assert_eq!(Config::load(&path).unwrap().audio, AudioOutput::default());
+
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        assert!(test_app.current_screen == CurrentScreen::Editing);
+        assert_eq!(test_app.edit_menu.state.selected(), Some(AUDIO_OUTPUT_ITEM));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    // the metronome thread should play its clicks on the chosen backend, the downbeat at the same volume as the other
+    // beats. The timing of the clicks is checked on a simulated clock in the metronome tests
+    #[test]
+    fn app_capture_playback() {
+        let capture = crate::audio::CaptureSink::default();
+        let settings = InitMetronomeSettings {
+            bpm: 240,
+            ..TEST_SETTINGS
+        };
+        let mut test_app = App::new(settings, 1_000);
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(capture.clone());
+        test_app.sound_dirs.clear();
+        test_app.init();
+        test_app.toggle_metronome();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while capture.ticks().len() < 3 {
+            assert!(Instant::now() < deadline, "the metronome didn't play");
+            thread::sleep(Duration::from_millis(10));
+        }
+        test_app.toggle_metronome();
+
+        // an error while running stops the metronome thread
+        test_app.settings.error.swap(true, Ordering::Relaxed);
+        test_app.settings.is_running.swap(true, Ordering::Relaxed);
+        test_app.metronome_handle.take().unwrap().join().unwrap();
+
+        let ticks = capture.ticks();
+        assert_eq!(ticks[0].peak(), ticks[1].peak());
+        assert_eq!(ticks[1].peak(), ticks[2].peak());
+    }
+
     // app::toggle_metronome should toggle metronome
     #[test]
     fn app_toggle_metronome() {
@@ -760,11 +2465,11 @@ mod tests {
         assert_eq!(test_app.get_is_running(), false);
     }
 
-    // app::get_ns_from_bpm should correctly calculate the nanosecond offset from bpm
+    // get_ns_from_bpm should correctly calculate the nanosecond offset from bpm
     #[test]
     fn app_get_ns_from_bpm() {
-        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
-        assert_eq!(test_app.get_ns_from_bpm(), 500_000_000);
+        let test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        assert_eq!(test_app.settings.get_ns_from_bpm(), 500_000_000);
     }
 
     // app::clear_strings should clear it's edit and notification strings when told to
@@ -794,6 +2499,92 @@ mod tests {
         assert_eq!(test_app.verify_bpm(20), true);
     }
 
+    // app::apply_preset should change the tempo and meter together
+    #[test]
+    fn app_apply_preset() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.apply_preset(&Preset {
+            name: "Waltz".to_string(),
+            bpm: 90,
+            ts_note: 3,
+            ts_value: 4,
+        });
+        assert_eq!(test_app.get_bpm(), 90);
+        assert_eq!(test_app.get_time_sig_string(), "3/4");
+        assert_eq!(test_app.settings.beats_per_bar.load(Ordering::Relaxed), 3);
+    }
+
+    // app::setlist_next should jump to the next song and restart the bar, starting the metronome with count-in on
+    #[test]
+    fn app_setlist_next() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.load_setlist(Setlist::parse("4/4 @120 \"One\"\n7/8 @140 \"Two\"").unwrap());
+        test_app.toggle_count_in();
+        test_app.setlist_next();
+        assert_eq!(test_app.get_bpm(), 140);
+        assert_eq!(test_app.get_setlist_string().unwrap(), "Two (2/2)");
+        assert!(test_app.get_is_running());
+        assert!(test_app.settings.restart.load(Ordering::Relaxed));
+    }
+
+    // app::load_song_map should start from the first section of the song
+    #[test]
+    fn app_load_song_map() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.load_song_map(SongMap {
+            sections: vec![crate::song_map::Section {
+                name: "Intro".to_string(),
+                bars: 4,
+                bpm: 96.0,
+                ts_note: 5,
+                ts_value: 4,
+            }],
+        });
+        assert_eq!(test_app.get_bpm(), 96);
+        assert_eq!(test_app.get_time_sig_string(), "5/4");
+        assert_eq!(test_app.get_section_string().unwrap(), "Intro (1/1)");
+        assert!(test_app.settings.restart.load(Ordering::Relaxed));
+    }
+
+    // a MIDI import warning should stay up while moving through the menus, until it is dismissed
+    #[test]
+    fn app_notification_stays_up() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.load_song_map(SongMap::parse("2 x 4/4 @120").unwrap());
+        let path = std::env::temp_dir().join("readymetronome_app_notification.mid");
+        let click_track = crate::click_track::build(&test_app.settings, None);
+        midi::export_click_track(&click_track, path.to_str().unwrap()).unwrap();
+        test_app.load_song_file(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        assert!(test_app.alert_string.contains("(warning: ignored"));
+
+        test_app.current_screen = CurrentScreen::Main;
+        test_app.update(KeyEvent::from(KeyCode::Down)).unwrap();
+        test_app.update(KeyEvent::from(KeyCode::Up)).unwrap();
+        assert!(test_app.alert_string.contains("(warning: ignored"));
+        test_app.update(KeyEvent::from(KeyCode::Esc)).unwrap();
+        assert!(test_app.alert_string.is_empty());
+    }
+
+    // app::change_section_editor should add, replace and remove song map sections from the edit_string
+    #[test]
+    fn app_change_section_editor() {
+        let mut test_app = App::new(TEST_SETTINGS, TEST_TICK_RATE);
+        test_app.edit_string = "8 x 4/4 @120 \"Verse\"".to_string();
+        assert!(test_app.change_section_editor(0));
+        test_app.edit_string = "4 x 7/8 @140 \"Bridge\"".to_string();
+        assert!(test_app.change_section_editor(1));
+        assert_eq!(test_app.get_section_line(1), "4 x 7/8 @140 \"Bridge\"");
+
+        test_app.edit_string = "4 x 7/8 140".to_string();
+        assert!(!test_app.change_section_editor(1));
+        assert!(test_app.alert_string.starts_with("column 9:"));
+
+        test_app.edit_string.clear();
+        assert!(test_app.change_section_editor(0));
+        assert_eq!(test_app.get_section_line(0), "4 x 7/8 @140 \"Bridge\"");
+    }
+
     // app::verify_volume should correctly determine which values are in range
     #[test]
     fn app_verify_volume() {
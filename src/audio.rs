@@ -0,0 +1,350 @@
+/// Audio.rs finds the audio hosts and output devices the click can be played on and opens them. A host is the
+/// system's audio API (ie ALSA or JACK on Linux, WASAPI or ASIO on Windows) and each host has its own devices.
+/// Clicks are mixed into the device's stream with rodio's mixer, the stream is built here rather than by rodio so
+/// errors from the device (ie it being unplugged) reach the metronome instead of being printed over the interface.
+/// The metronome plays on an AudioSink, so it can also run without a sound card on the null and capture backends.
+// cpal device docs: https://docs.rs/cpal/latest/cpal/traits/trait.HostTrait.html
+use crate::{
+    clock::{Clock, RealClock},
+    sounds::SoundSource,
+};
+use color_eyre::{eyre::eyre, Report, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SizedSample,
+};
+use rodio::{
+    dynamic_mixer::{self, DynamicMixer, DynamicMixerController},
+    Source,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+// Somewhere to play the click
+pub trait AudioSink {
+    // Start playing a sound, it is mixed with anything still playing
+    fn play(&self, source: SoundSource);
+
+    // The error the output reported, once it has failed it won't play anything else
+    fn error(&self) -> Option<String> {
+        None
+    }
+}
+
+// What the click is played on. Rodio plays on the audio device, null plays nothing and capture keeps every tick so
+// it can be checked afterwards, both of which work without a sound card (ie in tests and CI)
+#[derive(Clone, Default)]
+pub enum AudioBackend {
+    #[default]
+    Rodio,
+    Null,
+    Capture(CaptureSink),
+}
+
+impl AudioBackend {
+    pub fn open(&self, output: &AudioOutput) -> Result<Box<dyn AudioSink>, Report> {
+        match self {
+            AudioBackend::Rodio => Ok(Box::new(open_output(output)?)),
+            AudioBackend::Null => Ok(Box::new(NullSink)),
+            AudioBackend::Capture(sink) => Ok(Box::new(sink.clone())),
+        }
+    }
+}
+
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn play(&self, _source: SoundSource) {}
+}
+
+// A tick played on the capture backend, with the time it was played and the sound after volume was applied
+#[derive(Clone, Debug)]
+pub struct CapturedTick {
+    pub time: Instant,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>, // interleaved
+}
+
+impl CapturedTick {
+    pub fn peak(&self) -> f32 {
+        self.samples
+            .iter()
+            .fold(0.0_f32, |peak, value| peak.max(value.abs()))
+    }
+}
+
+// Records every tick played on it, clones share the same recording. Ticks are timed with the clock the metronome
+// runs on, so a simulated clock gives exact tick times
+#[derive(Clone)]
+pub struct CaptureSink {
+    ticks: Arc<Mutex<Vec<CapturedTick>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for CaptureSink {
+    fn default() -> CaptureSink {
+        CaptureSink::with_clock(Arc::new(RealClock))
+    }
+}
+
+impl CaptureSink {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> CaptureSink {
+        CaptureSink {
+            ticks: Arc::new(Mutex::new(Vec::new())),
+            clock,
+        }
+    }
+
+    pub fn ticks(&self) -> Vec<CapturedTick> {
+        self.ticks.lock().unwrap().clone()
+    }
+
+    // Write the time (from the first tick), peak level and length of each tick as CSV
+    pub fn write_csv(&self, path: &Path) -> Result<(), Report> {
+        let ticks = self.ticks();
+        let mut csv = "tick,time_ms,peak,length_ms\n".to_string();
+        for (index, tick) in ticks.iter().enumerate() {
+            let time = tick.time.duration_since(ticks[0].time).as_secs_f64() * 1000.0;
+            let frames = tick.samples.len() / tick.channels.max(1) as usize;
+            let length = frames as f64 / tick.sample_rate as f64 * 1000.0;
+            csv += &format!("{},{:.3},{:.4},{:.3}\n", index, time, tick.peak(), length);
+        }
+        fs::write(path, csv)?;
+        Ok(())
+    }
+}
+
+impl AudioSink for CaptureSink {
+    fn play(&self, source: SoundSource) {
+        // the time is taken before the sound is decoded so decoding doesn't make the tick look late
+        let time = self.clock.now();
+        let tick = CapturedTick {
+            time,
+            channels: source.channels(),
+            sample_rate: source.sample_rate(),
+            samples: source.collect(),
+        };
+        self.ticks.lock().unwrap().push(tick);
+    }
+}
+
+// The host and device to play the click on, None uses the system default
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioOutput {
+    pub host: Option<String>,
+    pub device: Option<String>,
+}
+
+impl AudioOutput {
+    // The name shown in the Status menu, ie "USB Audio (ALSA)"
+    pub fn label(&self) -> String {
+        self.device.clone().unwrap_or("Default device".to_string())
+            + " ("
+            + self.host.as_deref().unwrap_or("default host")
+            + ")"
+    }
+}
+
+// The names of the hosts available on this system
+pub fn host_names() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name().to_string())
+        .collect()
+}
+
+// The names of the output devices of a host (or the default host)
+pub fn device_names(host: Option<&str>) -> Result<Vec<String>, Report> {
+    let host = find_host(host)?;
+    let mut names = Vec::new();
+    for device in host.output_devices()? {
+        if let Ok(name) = device.name() {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// An open output device, the click stops when it is dropped
+pub struct AudioStream {
+    _stream: cpal::Stream,
+    mixer: Arc<DynamicMixerController<f32>>,
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl AudioSink for AudioStream {
+    fn play(&self, source: SoundSource) {
+        self.mixer.add(source);
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+// Open an output stream on the chosen device
+pub fn open_output(output: &AudioOutput) -> Result<AudioStream, Report> {
+    let host = find_host(output.host.as_deref())?;
+    let device = match &output.device {
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|found| found == *name))
+            .ok_or(eyre!("Could not find the audio device {}", name))?,
+        None => host.default_output_device().ok_or(eyre!(
+            "There is no default audio device to play the click on"
+        ))?,
+    };
+    let config = device
+        .default_output_config()
+        .map_err(|error| eyre!("Could not open the audio device: {}", error))?;
+    let (mixer, mixer_output) =
+        dynamic_mixer::mixer::<f32>(config.channels(), config.sample_rate().0);
+    let error = Arc::new(Mutex::new(None));
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer_output, &error),
+        cpal::SampleFormat::F64 => build_stream::<f64>(&device, &config, mixer_output, &error),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer_output, &error),
+        cpal::SampleFormat::I32 => build_stream::<i32>(&device, &config, mixer_output, &error),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer_output, &error),
+        cpal::SampleFormat::U8 => build_stream::<u8>(&device, &config, mixer_output, &error),
+        format => {
+            return Err(eyre!(
+                "The audio device uses an unsupported format ({})",
+                format
+            ))
+        }
+    }
+    .map_err(|error| eyre!("Could not open the audio device: {}", error))?;
+    stream
+        .play()
+        .map_err(|error| eyre!("Could not start the audio device: {}", error))?;
+    Ok(AudioStream {
+        _stream: stream,
+        mixer,
+        error,
+    })
+}
+
+// Feed the mixer to the device in whatever sample format it uses, and keep the first error it reports
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    mut mixer_output: DynamicMixer<f32>,
+    error: &Arc<Mutex<Option<String>>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let error = Arc::clone(error);
+    device.build_output_stream(
+        &config.config(),
+        move |data: &mut [T], _| {
+            for value in data.iter_mut() {
+                *value = T::from_sample(mixer_output.next().unwrap_or(0.0));
+            }
+        },
+        move |stream_error| {
+            error
+                .lock()
+                .unwrap()
+                .get_or_insert(stream_error.to_string());
+        },
+        None,
+    )
+}
+
+fn find_host(name: Option<&str>) -> Result<cpal::Host, Report> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name() == name)
+        .ok_or(eyre!("Could not find the audio host {}", name))?;
+    Ok(cpal::host_from_id(id)?)
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_output_label() {
+        assert_eq!(
+            AudioOutput::default().label(),
+            "Default device (default host)"
+        );
+        let output = AudioOutput {
+            host: Some("ALSA".to_string()),
+            device: Some("USB Audio".to_string()),
+        };
+        assert_eq!(output.label(), "USB Audio (ALSA)");
+    }
+
+    // the capture backend should keep every sound played on it, in order
+    #[test]
+    fn audio_capture_backend() {
+        let capture = CaptureSink::default();
+        let backend = AudioBackend::Capture(capture.clone());
+        let sink = backend.open(&AudioOutput::default()).unwrap();
+        sink.play(Box::new(rodio::buffer::SamplesBuffer::new(
+            1,
+            44_100,
+            vec![0.5_f32; 441],
+        )));
+        sink.play(Box::new(rodio::buffer::SamplesBuffer::new(
+            2,
+            44_100,
+            vec![-0.25_f32; 882],
+        )));
+        assert!(sink.error().is_none());
+        let ticks = capture.ticks();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[0].peak(), 0.5);
+        assert_eq!(ticks[1].channels, 2);
+        assert!(ticks[1].time >= ticks[0].time);
+
+        let path = std::env::temp_dir().join("readymetronome_capture_test.csv");
+        capture.write_csv(&path).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "tick,time_ms,peak,length_ms");
+        assert!(lines[1].starts_with("0,0.000,0.5000,10.000"));
+        assert!(lines[2].ends_with(",0.2500,10.000"));
+        fs::remove_file(&path).unwrap();
+
+        // nothing is played on the null backend, and it never fails
+        let sink = AudioBackend::Null.open(&AudioOutput::default()).unwrap();
+        sink.play(Box::new(rodio::buffer::SamplesBuffer::new(
+            1,
+            44_100,
+            vec![0.5_f32],
+        )));
+        assert!(sink.error().is_none());
+    }
+
+    // a device or host that isn't there should be an error rather than a panic
+    #[test]
+    fn audio_open_missing_output() {
+        let output = AudioOutput {
+            host: None,
+            device: Some("Not a device".to_string()),
+        };
+        assert!(open_output(&output).is_err());
+        let output = AudioOutput {
+            host: Some("Not a host".to_string()),
+            device: None,
+        };
+        let error = open_output(&output).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Could not find the audio host Not a host"
+        );
+        assert!(device_names(Some("Not a host")).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+/// Big_text.rs draws text in large block characters for stage mode, so the tempo and position in the song can be read
+/// from across the room. Each character is a small bitmap that is scaled up to fill as much of the terminal as fits.
+/// Terminal cells are about twice as tall as they are wide, so each pixel is drawn two cells wide to keep it square.
+// The idea comes from tui-big-text: https://github.com/joshka/tui-big-text
+// Height of every glyph in pixels, the width depends on the glyph
+const GLYPH_HEIGHT: u16 = 5;
+
+// The bitmap of a character, '#' is a filled pixel. Characters without a glyph are drawn as a space
+fn glyph(character: char) -> [&'static str; GLYPH_HEIGHT as usize] {
+    match character {
+        '0' => ["###", "# #", "# #", "# #", "###"],
+        '1' => [" # ", "## ", " # ", " # ", "###"],
+        '2' => ["###", "  #", "###", "#  ", "###"],
+        '3' => ["###", "  #", "###", "  #", "###"],
+        '4' => ["# #", "# #", "###", "  #", "  #"],
+        '5' => ["###", "#  ", "###", "  #", "###"],
+        '6' => ["###", "#  ", "###", "# #", "###"],
+        '7' => ["###", "  #", "  #", "  #", "  #"],
+        '8' => ["###", "# #", "###", "# #", "###"],
+        '9' => ["###", "# #", "###", "  #", "###"],
+        '/' => ["  #", "  #", " # ", "#  ", "#  "],
+        '.' => [" ", " ", " ", " ", "#"],
+        '-' => ["   ", "   ", "###", "   ", "   "],
+        'B' => ["## ", "# #", "## ", "# #", "## "],
+        'P' => ["###", "# #", "###", "#  ", "#  "],
+        'M' => ["#   #", "## ##", "# # #", "#   #", "#   #"],
+        _ => ["  ", "  ", "  ", "  ", "  "],
+    }
+}
+
+// The size of some text in terminal cells at a scale, with a one pixel gap between characters
+pub fn size(text: &str, scale: u16) -> (u16, u16) {
+    let pixels: u16 = text
+        .chars()
+        .map(|character| glyph(character)[0].len() as u16)
+        .sum::<u16>()
+        + text.chars().count().saturating_sub(1) as u16;
+    (pixels * 2 * scale, GLYPH_HEIGHT * scale)
+}
+
+// The largest scale that fits all the lines (stacked with a gap of one scaled pixel) in the area, 0 if even the
+// smallest doesn't fit
+pub fn fit_scale(lines: &[&str], width: u16, height: u16) -> u16 {
+    let mut scale = 0;
+    loop {
+        let next = scale + 1;
+        let fits_width = lines.iter().all(|line| size(line, next).0 <= width);
+        let total_height = lines.len() as u16 * (GLYPH_HEIGHT + 1) * next - next;
+        if !fits_width || total_height > height {
+            return scale;
+        }
+        scale = next;
+    }
+}
+
+// Draw text as rows of block characters
+pub fn render(text: &str, scale: u16) -> Vec<String> {
+    let mut rows = Vec::new();
+    for pixel_row in 0..GLYPH_HEIGHT as usize {
+        let mut row = String::new();
+        for (index, character) in text.chars().enumerate() {
+            if index > 0 {
+                row += &" ".repeat(2 * scale as usize);
+            }
+            for pixel in glyph(character)[pixel_row].chars() {
+                let cell = if pixel == '#' { "█" } else { " " };
+                row += &cell.repeat(2 * scale as usize);
+            }
+        }
+        for _ in 0..scale {
+            rows.push(row.clone());
+        }
+    }
+    rows
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn big_text_render() {
+        assert_eq!(
+            render("1", 1),
+            vec!["  ██  ", "████  ", "  ██  ", "  ██  ", "██████"]
+        );
+        let rows = render("4/4", 2);
+        assert_eq!(rows.len(), 10);
+        assert_eq!(rows[0].chars().count() as u16, size("4/4", 2).0);
+        assert_eq!(size("120", 1), (22, 5));
+    }
+
+    // the text should grow with the terminal, and not be drawn at all when there is no room
+    #[test]
+    fn big_text_fit_scale() {
+        let lines = ["120", "4/4"];
+        assert_eq!(fit_scale(&lines, 80, 24), 2);
+        assert_eq!(fit_scale(&lines, 200, 60), 5);
+        assert_eq!(fit_scale(&lines, 20, 24), 0);
+    }
+}
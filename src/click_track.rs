@@ -0,0 +1,67 @@
+/// Click_track.rs lays out the clicks the metronome would play for a number of bars without actually playing them. It
+/// is used to export the click to other programs, and uses the same settings and timing code as live playback.
+use crate::metronome::{tick_kind, MetronomeSettings, TickKind};
+use std::sync::atomic::Ordering;
+
+// One bar of the click track and the kind of each click in it
+pub struct Bar {
+    pub section_name: Option<String>, // set on the first bar of each song map section
+    pub ts_note: u64,
+    pub ts_value: u64,
+    pub ns_delay: u64,
+    pub clicks: Vec<TickKind>,
+}
+
+impl Bar {
+    pub fn length_ns(&self) -> u64 {
+        self.ns_delay * self.clicks.len() as u64
+    }
+}
+
+// Lay out the click for the given number of bars. If a song map is loaded it is followed from the first bar and the
+// click track ends with the song, without one it defaults to the length of the song or 8 bars
+pub fn build(settings: &MetronomeSettings, bars: Option<u64>) -> Vec<Bar> {
+    let song_map = settings.song_map.read().unwrap().clone();
+    let num_bars = match (bars, &song_map) {
+        (Some(value), _) => value,
+        (None, Some(map)) => map.sections.iter().map(|section| section.bars).sum(),
+        (None, None) => 8,
+    };
+    let accents = settings.accents.load(Ordering::Relaxed);
+    // each bar's meter and tempo are worked out here rather than set on the metronome, so exporting a song map doesn't
+    // change what is playing
+    let mut ts_note = settings.ts_note.load(Ordering::Relaxed);
+    let mut ts_value = settings.ts_value.load(Ordering::Relaxed);
+    let mut ns_delay = settings.get_ns_for_note_value();
+
+    let mut click_track = Vec::new();
+    let mut last_section = None;
+    for bar in 1..=num_bars {
+        let mut section_name = None;
+        if let Some(map) = &song_map {
+            match map.section_at_bar(bar) {
+                Some((index, section)) => {
+                    (ts_note, ts_value) = (section.ts_note, section.ts_value);
+                    ns_delay = settings.ns_for_bpm(section.bpm, ts_value);
+                    if last_section != Some(index) {
+                        section_name = Some(section.name.clone());
+                        last_section = Some(index);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let beats_per_bar = settings.beats_per_bar_for(ts_note);
+        click_track.push(Bar {
+            section_name,
+            ts_note,
+            ts_value,
+            ns_delay,
+            clicks: (0..beats_per_bar)
+                .map(|tick| tick_kind(tick, ts_note, beats_per_bar, accents))
+                .collect(),
+        });
+    }
+    click_track
+}
@@ -0,0 +1,67 @@
+/// Clock.rs is where the metronome gets the time from. The real clock reads the system clock and sleeps the thread,
+/// while the simulated clock only moves when it is told to sleep, so tests can run the metronome through minutes of
+/// virtual time instantly and know exactly when every tick should land.
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    // spin_sleep is much more accurate than thread::sleep for the short waits between metronome steps
+    fn sleep(&self, duration: Duration) {
+        spin_sleep::sleep(duration);
+    }
+}
+
+// Virtual time starting from when the clock was made, clones share the same time
+#[cfg(test)]
+#[derive(Clone)]
+pub struct SimulatedClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new() -> SimulatedClock {
+        SimulatedClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_clock() {
+        let clock = SimulatedClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        shared.sleep(Duration::from_secs(90));
+        assert_eq!(clock.now().duration_since(start), Duration::from_secs(90));
+    }
+}
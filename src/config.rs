@@ -0,0 +1,99 @@
+/// Config.rs loads and saves the settings Ready Metronome remembers between runs. They are kept in a toml file at
+/// $XDG_CONFIG_HOME/readymetronome/config.toml (or ~/.config/readymetronome/config.toml), for example:
+///
+///     latency_offset = 40  # ms
+///     flash_downbeat = true
+///
+///     [audio]
+///     host = "ALSA"
+///     device = "USB Audio"
+///
+///     [sounds."EmeryBoardClick.wav"]
+///     trim = true
+///     normalize = "peak"  # off, peak or rms
+///     pitch = -2.0        # semitones
+use crate::{audio::AudioOutput, processing::SoundProcessing};
+use color_eyre::{eyre::eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub latency_offset: i64, // ms to show the beat after the click, for the output's latency
+    pub flash_downbeat: bool, // flash the beat indicator on beat 1
+    pub audio: AudioOutput,  // the output device to click on
+    pub sounds: BTreeMap<String, SoundProcessing>, // processing of each sound by name, unchanged sounds are left out
+}
+
+// Where the config file lives, None if there is no home folder to put it in
+pub fn config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(value) if !value.is_empty() => Some(PathBuf::from(value)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    };
+    config_home.map(|dir| dir.join("readymetronome").join("config.toml"))
+}
+
+impl Config {
+    // A missing config file just means nothing has been changed yet
+    pub fn load(path: &Path) -> Result<Config, Report> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|error| eyre!("{}: {}", path.display(), error.message()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Report> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::Normalize;
+
+    #[test]
+    fn config_save_and_load() {
+        let path = env::temp_dir().join("readymetronome_config_test/config.toml");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        let mut config = Config {
+            latency_offset: -25,
+            ..Default::default()
+        };
+        config.audio.device = Some("USB Audio".to_string());
+        config.sounds.insert(
+            "Wood Blocks/high.wav".to_string(),
+            SoundProcessing {
+                trim: true,
+                normalize: Normalize::Rms,
+                pitch: -2.0,
+            },
+        );
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+
+        // settings that aren't given fall back to their defaults
+        fs::write(&path, "[sounds.\"a.wav\"]\nnormalize = \"peak\"\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.sounds["a.wav"].normalize, Normalize::Peak);
+        assert!(!config.sounds["a.wav"].trim);
+
+        fs::write(&path, "[sounds.\"a.wav\"]\nnormalize = \"loud\"\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -26,13 +26,19 @@ pub fn run_app<B: Backend>(
         terminal.draw(|f| ui(f, app))?;
 
         match events.next()? {
-            Event::Tick => {}
+            Event::Tick => {
+                app.play_due_preview();
+                app.check_sound_folders();
+            }
             Event::Key(key_event) => match app.update(key_event) {
                 Ok(_) => {}
                 Err(e) => return Err(e),
             },
-            Event::Mouse(_) => {}
-            Event::Resize(_, _) => {}
+            Event::Mouse(mouse_event) => match app.mouse(mouse_event) {
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            },
+            Event::Resize(width, height) => app.resize(width, height),
             Event::FocusChange(_) => {}
         }
     }
@@ -0,0 +1,454 @@
+/// Keymap.rs is the table of every key binding in Ready Metronome. App::update looks up the binding for a key press on
+/// the current screen and runs its action, and the help overlay (?) is generated from the same table, so the help
+/// always matches what the keys actually do.
+use crate::app::CurrentScreen;
+use crossterm::event::KeyCode;
+
+// Something a key press can do, carried out by App::run_action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Previous,
+    Next,
+    Back,
+    Select,
+    Save,
+    Type,
+    BpmUp,
+    BpmDown,
+    ToggleMetronome,
+    PreviousSong,
+    NextSong,
+    ToggleCountIn,
+    ToggleStageMode,
+    ToggleMute,
+    AskToQuit,
+    Quit,
+    StayOpen,
+    ChooseOutput,
+    RetryOutput,
+    Tap,
+    ApplyCalibration,
+    ReportError,
+    OpenHelp,
+    ScrollHelpUp,
+    ScrollHelpDown,
+    CloseHelp,
+    DismissNotification,
+}
+
+// The keys that trigger a binding
+pub enum Keys {
+    Codes(&'static [KeyCode]),
+    AnyCharacter,
+}
+
+// When a binding applies, depending on whether a value is being edited in a pop up
+pub enum Mode {
+    Any,
+    Browsing,      // no value is being edited
+    Typing,        // a value is being edited
+    NotTypingText, // anything but typing a file path or song map section, where every character is part of the text
+}
+
+pub struct Binding {
+    pub keys: Keys,
+    pub screens: &'static [CurrentScreen],
+    pub mode: Mode,
+    pub action: Action,
+    pub description: &'static str,
+}
+
+impl Binding {
+    fn matches(&self, key: KeyCode) -> bool {
+        match self.keys {
+            Keys::Codes(codes) => codes.contains(&key),
+            Keys::AnyCharacter => matches!(key, KeyCode::Char(_)),
+        }
+    }
+
+    fn applies(&self, screen: &CurrentScreen, editing: bool, text_input: bool) -> bool {
+        let mode = match self.mode {
+            Mode::Any => true,
+            Mode::Browsing => !editing,
+            Mode::Typing => editing,
+            Mode::NotTypingText => !text_input,
+        };
+        mode && self.screens.contains(screen)
+    }
+
+    // How the keys are written in the help, ie "up / left / shift-tab"
+    pub fn keys_label(&self) -> String {
+        match self.keys {
+            Keys::Codes(codes) => codes.iter().map(key_name).collect::<Vec<_>>().join(" / "),
+            Keys::AnyCharacter => "any character".to_string(),
+        }
+    }
+}
+
+fn key_name(key: &KeyCode) -> String {
+    match key {
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(character) => character.to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        KeyCode::BackTab => "shift-tab".to_string(),
+        KeyCode::Enter => "enter".to_string(),
+        KeyCode::Esc => "esc".to_string(),
+        KeyCode::Backspace => "backspace".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+const ALL: &[CurrentScreen] = &[
+    CurrentScreen::Main,
+    CurrentScreen::Editing,
+    CurrentScreen::Exiting,
+    CurrentScreen::SoundSelection,
+    CurrentScreen::SongMap,
+    CurrentScreen::AudioSettings,
+    CurrentScreen::AudioError,
+    CurrentScreen::Calibration,
+    CurrentScreen::Error,
+];
+// The screens with a menu to move through
+const MENUS: &[CurrentScreen] = &[
+    CurrentScreen::Main,
+    CurrentScreen::Editing,
+    CurrentScreen::SoundSelection,
+    CurrentScreen::SongMap,
+    CurrentScreen::AudioSettings,
+];
+// The screens with a value to type in
+const EDITORS: &[CurrentScreen] = &[
+    CurrentScreen::Editing,
+    CurrentScreen::SoundSelection,
+    CurrentScreen::SongMap,
+];
+
+// Every key binding. The first binding that matches a key press on the current screen is the one that runs, so the
+// global shortcuts come first and the screen specific keys after them
+pub const BINDINGS: &[Binding] = &[
+    // menu navigation
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Up, KeyCode::Left, KeyCode::BackTab]),
+        screens: MENUS,
+        mode: Mode::Browsing,
+        action: Action::Previous,
+        description: "Move to the previous item",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Down, KeyCode::Right, KeyCode::Tab]),
+        screens: MENUS,
+        mode: Mode::Browsing,
+        action: Action::Next,
+        description: "Move to the next item",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Esc]),
+        screens: EDITORS,
+        mode: Mode::Typing,
+        action: Action::Back,
+        description: "Discard the value being edited",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Esc]),
+        screens: &[
+            CurrentScreen::Editing,
+            CurrentScreen::SoundSelection,
+            CurrentScreen::SongMap,
+            CurrentScreen::AudioSettings,
+            CurrentScreen::Calibration,
+        ],
+        mode: Mode::Browsing,
+        action: Action::Back,
+        description: "Go back to the previous menu",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Esc]),
+        screens: &[CurrentScreen::Main],
+        mode: Mode::Browsing,
+        action: Action::DismissNotification,
+        description: "Dismiss the notification",
+    },
+    // global shortcuts
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('+')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::BpmUp,
+        description: "Raise the bpm by 10",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('-')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::BpmDown,
+        description: "Lower the bpm by 10",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('t')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::ToggleMetronome,
+        description: "Start / stop the metronome",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('[')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::PreviousSong,
+        description: "Go to the previous song in the setlist",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char(']')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::NextSong,
+        description: "Go to the next song in the setlist",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('c')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::ToggleCountIn,
+        description: "Turn the count-in bar on / off",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('s')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::ToggleStageMode,
+        description: "Turn stage mode on / off",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('m')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::ToggleMute,
+        description: "Mute / unmute the click",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('?')]),
+        screens: ALL,
+        mode: Mode::Browsing,
+        action: Action::OpenHelp,
+        description: "Show the keys for this screen",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('q')]),
+        screens: &[
+            CurrentScreen::Main,
+            CurrentScreen::Editing,
+            CurrentScreen::SoundSelection,
+            CurrentScreen::SongMap,
+            CurrentScreen::AudioSettings,
+            CurrentScreen::AudioError,
+            CurrentScreen::Calibration,
+            CurrentScreen::Error,
+        ],
+        mode: Mode::NotTypingText,
+        action: Action::AskToQuit,
+        description: "Quit, after asking to make sure",
+    },
+    // choosing and editing
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Enter]),
+        screens: MENUS,
+        mode: Mode::Browsing,
+        action: Action::Select,
+        description: "Choose the highlighted item",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Enter]),
+        screens: EDITORS,
+        mode: Mode::Typing,
+        action: Action::Save,
+        description: "Save the value being edited",
+    },
+    Binding {
+        keys: Keys::AnyCharacter,
+        screens: EDITORS,
+        mode: Mode::Typing,
+        action: Action::Type,
+        description: "Type the value",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Backspace]),
+        screens: EDITORS,
+        mode: Mode::Typing,
+        action: Action::Type,
+        description: "Delete the last character",
+    },
+    // audio error screen
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Enter]),
+        screens: &[CurrentScreen::AudioError],
+        mode: Mode::Any,
+        action: Action::ChooseOutput,
+        description: "Choose another audio output",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('r')]),
+        screens: &[CurrentScreen::AudioError],
+        mode: Mode::Any,
+        action: Action::RetryOutput,
+        description: "Try the audio output again",
+    },
+    // calibration screen
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char(' ')]),
+        screens: &[CurrentScreen::Calibration],
+        mode: Mode::Any,
+        action: Action::Tap,
+        description: "Tap along with the click",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Enter]),
+        screens: &[CurrentScreen::Calibration],
+        mode: Mode::Any,
+        action: Action::ApplyCalibration,
+        description: "Use the suggested latency offset",
+    },
+    // quit pop up
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Char('y'), KeyCode::Char('q'), KeyCode::Enter]),
+        screens: &[CurrentScreen::Exiting],
+        mode: Mode::Any,
+        action: Action::Quit,
+        description: "Quit Ready Metronome",
+    },
+    Binding {
+        keys: Keys::Codes(&[
+            KeyCode::Char('n'),
+            KeyCode::Backspace,
+            KeyCode::Esc,
+            KeyCode::Tab,
+        ]),
+        screens: &[CurrentScreen::Exiting],
+        mode: Mode::Any,
+        action: Action::StayOpen,
+        description: "Go back to the main menu",
+    },
+    // error screen
+    Binding {
+        keys: Keys::AnyCharacter,
+        screens: &[CurrentScreen::Error],
+        mode: Mode::Any,
+        action: Action::ReportError,
+        description: "Quit and show what went wrong",
+    },
+];
+
+// The keys of the help overlay, which takes every key press while it is open
+pub const HELP_BINDINGS: &[Binding] = &[
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Up]),
+        screens: ALL,
+        mode: Mode::Any,
+        action: Action::ScrollHelpUp,
+        description: "Scroll the help up",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Down]),
+        screens: ALL,
+        mode: Mode::Any,
+        action: Action::ScrollHelpDown,
+        description: "Scroll the help down",
+    },
+    Binding {
+        keys: Keys::Codes(&[KeyCode::Esc, KeyCode::Char('?')]),
+        screens: ALL,
+        mode: Mode::Any,
+        action: Action::CloseHelp,
+        description: "Close the help",
+    },
+];
+
+// The binding that handles a key press, if any
+pub fn find(
+    bindings: &'static [Binding],
+    screen: &CurrentScreen,
+    editing: bool,
+    text_input: bool,
+    key: KeyCode,
+) -> Option<&'static Binding> {
+    bindings
+        .iter()
+        .find(|binding| binding.matches(key) && binding.applies(screen, editing, text_input))
+}
+
+// The keys and descriptions of every binding on a screen for the help overlay, followed by how to use the overlay
+pub fn help_lines(screen: &CurrentScreen) -> Vec<(String, &'static str)> {
+    BINDINGS
+        .iter()
+        .filter(|binding| binding.screens.contains(screen))
+        .chain(HELP_BINDINGS)
+        .map(|binding| (binding.keys_label(), binding.description))
+        .collect()
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a key press should find the binding for its screen and whether a value is being edited
+    #[test]
+    fn keymap_find() {
+        let action = |screen, editing, text_input, key| {
+            find(BINDINGS, &screen, editing, text_input, key).map(|binding| binding.action)
+        };
+        use CurrentScreen::*;
+        assert_eq!(
+            action(Main, false, false, KeyCode::Enter),
+            Some(Action::Select)
+        );
+        assert_eq!(
+            action(Editing, true, false, KeyCode::Enter),
+            Some(Action::Save)
+        );
+        assert_eq!(
+            action(Editing, false, false, KeyCode::Char('t')),
+            Some(Action::ToggleMetronome)
+        );
+        assert_eq!(
+            action(Editing, true, false, KeyCode::Char('t')),
+            Some(Action::Type)
+        );
+        // q asks to quit unless it is part of a file path or section being typed
+        assert_eq!(
+            action(Editing, true, false, KeyCode::Char('q')),
+            Some(Action::AskToQuit)
+        );
+        assert_eq!(
+            action(SongMap, true, true, KeyCode::Char('q')),
+            Some(Action::Type)
+        );
+        assert_eq!(
+            action(Exiting, false, false, KeyCode::Char('q')),
+            Some(Action::Quit)
+        );
+        assert_eq!(
+            action(Main, false, false, KeyCode::Esc),
+            Some(Action::DismissNotification)
+        );
+        assert_eq!(
+            action(Error, false, false, KeyCode::Char('x')),
+            Some(Action::ReportError)
+        );
+    }
+
+    #[test]
+    fn keymap_help_lines() {
+        let lines = help_lines(&CurrentScreen::Calibration);
+        assert!(lines.contains(&("space".to_string(), "Tap along with the click")));
+        assert!(lines.contains(&("esc".to_string(), "Go back to the previous menu")));
+        assert!(lines.contains(&("esc / ?".to_string(), "Close the help")));
+        assert!(!lines.iter().any(|(keys, _)| keys == "y / q / enter"));
+        let lines = help_lines(&CurrentScreen::Main);
+        assert_eq!(lines[0].0, "up / left / shift-tab");
+    }
+}
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 /// This file is the main entrypoint and handles starting the app as well as initializing
 /// and cleaning up the ratatui interface.
 use crossterm::{
@@ -7,19 +7,75 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
-use std::{error::Error, io};
+use std::{
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+};
 
 mod app;
+mod audio;
 mod ui;
-use crate::{app::App, events::run_app, metronome::InitMetronomeSettings};
+use crate::{
+    app::App,
+    events::run_app,
+    metronome::InitMetronomeSettings,
+    setlist::{parse_bpm, parse_time_signature, Setlist},
+};
 
+mod big_text;
+mod click_track;
+mod clock;
+mod config;
 mod event_handler;
 mod events;
+mod keymap;
 mod menu;
 mod metronome;
+mod midi;
+mod processing;
+mod render;
+mod setlist;
+mod song_map;
+mod sounds;
+mod synth;
+mod timing;
+
+// Initialize the app
+const APP_REFRESH_RATE_NS: u64 = 1_000;
+const UI_REFRESH_RATE_MS: u64 = 7;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+    let (ts_note, ts_value) = args.time_signature;
+    let init_settings: InitMetronomeSettings = InitMetronomeSettings {
+        bpm: args.bpm,
+        ts_note,
+        ts_value,
+        volume: args.volume,
+        is_running: false,
+        debug: args.debug,
+        count_in: args.count_in,
+        ts_triplets: args.subdivision == Some(Subdivision::Triplets),
+        sub_eights: matches!(
+            args.subdivision,
+            Some(Subdivision::Eighths | Subdivision::Triplets)
+        ),
+        sub_sixteens: args.subdivision == Some(Subdivision::Sixteenths),
+        synth_pitch: args.synth_pitch,
+        synth_decay: args.synth_decay,
+    };
+
+    // Commands that run without the terminal interface
+    if let Some(command) = &args.command {
+        return run_command(command, init_settings, &args);
+    }
+
+    // Load the setlist before taking over the terminal so any problems with the file are printed normally
+    let setlist = match &args.setlist {
+        Some(path) => Some(Setlist::load(path)?),
+        None => None,
+    };
 
     // This is neccessary Ratatui boilerplate, enables Ratatui to have control over the keyboard inputs as well as mouse
     enable_raw_mode()?;
@@ -29,20 +85,48 @@ fn main() -> Result<(), Box<dyn Error>> {
     let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
-    // Initialize the app
-    const APP_REFRESH_RATE_NS: u64 = 1_000;
-    const UI_REFRESH_RATE_MS: u64 = 7;
-    let init_settings: InitMetronomeSettings = InitMetronomeSettings {
-        bpm: 120,
-        ts_note: 4,
-        ts_value: 4,
-        volume: 100.0,
-        is_running: false,
-        debug: args.debug,
-    };
-
     let mut app = App::new(init_settings, APP_REFRESH_RATE_NS);
+    let size = terminal.size()?;
+    app.resize(size.width, size.height);
+    let capture = audio::CaptureSink::default();
+    *app.settings.audio_backend.write().unwrap() = match args.audio_backend {
+        Backend::Rodio => audio::AudioBackend::Rodio,
+        Backend::Null => audio::AudioBackend::Null,
+        Backend::Capture => audio::AudioBackend::Capture(capture.clone()),
+    };
+    app.sound_dirs = sounds::sound_dirs(args.assets_dir.as_deref());
+    app.config_path = args
+        .config
+        .clone()
+        .map(PathBuf::from)
+        .or_else(config::config_path);
+    if let Err(error) = app.load_config() {
+        app.alert_string = "Could not load settings: ".to_owned() + &error.to_string();
+    }
+    if args.host.is_some() || args.device.is_some() {
+        app.set_audio_output(audio::AudioOutput {
+            host: args.host.clone(),
+            device: args.device.clone(),
+        });
+        app.save_config();
+    }
     app.init();
+    if let Some(setlist) = setlist {
+        app.load_setlist(setlist);
+    }
+    if let Some(path) = &args.song {
+        app.load_song_file(path);
+    }
+    for path in &args.add_sound {
+        if let Err(error) = app.add_sound(path) {
+            app.alert_string = error.to_string();
+        }
+    }
+    if let Some(name) = &args.sound {
+        if let Err(error) = app.select_sound(name) {
+            app.alert_string = error.to_string();
+        }
+    }
 
     let res = run_app(&mut terminal, &mut app, UI_REFRESH_RATE_MS);
     // This begins the clean up phase after the app quits
@@ -62,13 +146,209 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("{err:?}");
     }
 
+    if args.debug {
+        let tick_timings = app.settings.tick_timings.lock().unwrap();
+        tick_timings.write_csv(Path::new(&args.jitter_out))?;
+        println!(
+            "Wrote the timing of {} ticks to {}",
+            tick_timings.ticks().len(),
+            args.jitter_out
+        );
+    }
+
+    if args.audio_backend == Backend::Capture {
+        capture.write_csv(Path::new(&args.capture_out))?;
+        println!(
+            "Wrote {} captured ticks to {}",
+            capture.ticks().len(),
+            args.capture_out
+        );
+    }
+
+    Ok(())
+}
+
+// Run one of the command line only commands, ie exporting the click to a MIDI file
+fn run_command(
+    command: &Command,
+    init_settings: InitMetronomeSettings,
+    args: &Args,
+) -> Result<(), Box<dyn Error>> {
+    let mut app = App::new(init_settings, APP_REFRESH_RATE_NS);
+    if let Some(path) = &args.song {
+        app.load_song_file(path);
+        if app.settings.song_map.read().unwrap().is_none() {
+            eprintln!("{}", app.alert_string);
+            std::process::exit(1);
+        }
+        if !app.alert_string.is_empty() {
+            println!("{}", app.alert_string);
+        }
+    }
+    match command {
+        Command::Export { bars, out } => {
+            let click_track = click_track::build(&app.settings, *bars);
+            midi::export_click_track(&click_track, out)?;
+            println!("Exported {} bars of click to {}", click_track.len(), out);
+        }
+        Command::Devices => {
+            for host in audio::host_names() {
+                println!("{}", host);
+                for device in audio::device_names(Some(&host))? {
+                    println!("    {}", device);
+                }
+            }
+        }
+        Command::Render { bars, out } => {
+            app.sound_dirs = sounds::sound_dirs(args.assets_dir.as_deref());
+            app.populate_sounds()?;
+            app.config_path = args
+                .config
+                .clone()
+                .map(PathBuf::from)
+                .or_else(config::config_path);
+            app.load_config()?;
+            for reason in &app.skipped_sounds {
+                eprintln!("Skipped {}", reason);
+            }
+            for path in &args.add_sound {
+                app.add_sound(path)?;
+            }
+            if let Some(name) = &args.sound {
+                app.select_sound(name)?;
+            }
+            let click_track = click_track::build(&app.settings, *bars);
+            let volume = app.get_volume();
+            let open_sound = |kind| app.settings.open_selected_sound(kind);
+            render::render_wav(&click_track, open_sound, volume, out)?;
+            println!("Rendered {} bars of click to {}", click_track.len(), out);
+        }
+    }
     Ok(())
 }
 
+fn parse_volume(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(volume) if (1.0..=200.0).contains(&volume) => Ok(volume),
+        _ => Err(format!("'{}' is not a volume between 1.0 and 200.0", value)),
+    }
+}
+
+fn parse_synth_pitch(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(pitch) if synth::PITCH_RANGE.contains(&pitch) => Ok(pitch),
+        _ => Err(format!("'{}' is not a pitch between -24 and 24", value)),
+    }
+}
+
+fn parse_synth_decay(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(decay) if synth::DECAY_RANGE.contains(&decay) => Ok(decay),
+        _ => Err(format!("'{}' is not a decay between 5 and 1000", value)),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Whether or not we are in debug mode
     #[arg(short, long)]
     debug: bool,
+    /// Load a setlist file to step through with '[' and ']'
+    #[arg(short, long, value_name = "FILE")]
+    setlist: Option<String>,
+    /// Load a song map (or the tempo and meter changes of a .mid file) for the metronome to follow
+    #[arg(long, value_name = "FILE")]
+    song: Option<String>,
+    /// Play a bar of count-in when changing songs
+    #[arg(short, long)]
+    count_in: bool,
+    /// Starting tempo in beats per minute
+    #[arg(short, long, default_value_t = 120, value_parser = parse_bpm)]
+    bpm: u64,
+    /// Starting time signature, ie 7/8
+    #[arg(short, long, default_value = "4/4", value_parser = parse_time_signature)]
+    time_signature: (u64, u64),
+    /// Name of the sound to start with, ie EmeryBoardClick.wav or "Sine blip"
+    #[arg(long, value_name = "NAME")]
+    sound: Option<String>,
+    /// Folder to look for sounds in before the default sound folders
+    #[arg(long, value_name = "DIR")]
+    assets_dir: Option<String>,
+    /// Add a sound file from anywhere on disk and select it, can be given more than once
+    #[arg(long, value_name = "FILE")]
+    add_sound: Vec<String>,
+    /// Config file to remember settings in, defaults to $XDG_CONFIG_HOME/readymetronome/config.toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+    /// Audio host to play the click on, ie ALSA or JACK (see the devices command)
+    #[arg(long, value_name = "NAME")]
+    host: Option<String>,
+    /// Audio output device to play the click on, remembered for next time (see the devices command)
+    #[arg(long, value_name = "NAME")]
+    device: Option<String>,
+    /// What to play the click on, null and capture don't need a sound card (ie for testing)
+    #[arg(long, value_enum, default_value_t = Backend::Rodio)]
+    audio_backend: Backend,
+    /// File to write the time and level of every tick to when using the capture backend
+    #[arg(long, value_name = "FILE", default_value = "capture.csv")]
+    capture_out: String,
+    /// File to write the scheduled and actual time of every tick to on exit in debug mode, numbered by run (each start)
+    #[arg(long, value_name = "FILE", default_value = "jitter.csv")]
+    jitter_out: String,
+    /// Starting volume in percent
+    #[arg(long, default_value_t = 100.0, value_parser = parse_volume)]
+    volume: f64,
+    /// Pitch of the synth voices in semitones, ie -12 for an octave down
+    #[arg(long, default_value_t = 0.0, value_parser = parse_synth_pitch, allow_negative_numbers = true)]
+    synth_pitch: f64,
+    /// Decay time of the synth voices in milliseconds
+    #[arg(long, default_value_t = 40.0, value_parser = parse_synth_decay)]
+    synth_decay: f64,
+    /// Subdivide the click between beats
+    #[arg(long, value_enum)]
+    subdivision: Option<Subdivision>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the click as a Standard MIDI File to drop into a DAW
+    Export {
+        /// Number of bars to export (defaults to the length of the song map, or 8 bars)
+        #[arg(long)]
+        bars: Option<u64>,
+        /// File to write
+        #[arg(short, long, value_name = "FILE")]
+        out: String,
+    },
+    /// List the audio hosts and output devices the click can be played on
+    Devices,
+    /// Render the click to a WAV file without opening an audio device
+    Render {
+        /// Number of bars to render (defaults to the length of the song map, or 8 bars)
+        #[arg(long)]
+        bars: Option<u64>,
+        /// File to write
+        #[arg(short, long, value_name = "FILE")]
+        out: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Backend {
+    /// The audio device
+    Rodio,
+    /// Nothing, the metronome runs silently
+    Null,
+    /// Record every tick, written to --capture-out on exit
+    Capture,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Subdivision {
+    Eighths,
+    Sixteenths,
+    Triplets,
 }
@@ -3,7 +3,10 @@
 // References
 // List state / Menu reference: https://docs.rs/ratatui/latest/ratatui/widgets/trait.StatefulWidget.html
 // List: https://docs.rs/ratatui/latest/ratatui/widgets/struct.List.html
-use ratatui::widgets::ListState;
+use ratatui::{
+    layout::{Margin, Position, Rect},
+    widgets::ListState,
+};
 
 pub struct Menu {
     pub items: Vec<String>,
@@ -58,4 +61,13 @@ impl Menu {
     pub fn select(&mut self, index: usize) {
         self.state.select(Some(index));
     }
+    // The index of the item drawn at a terminal cell, when the menu was drawn as a bordered list in area
+    pub fn item_at(&self, area: Rect, column: u16, row: u16) -> Option<usize> {
+        let inner = area.inner(Margin::new(1, 1));
+        if !inner.contains(Position::new(column, row)) {
+            return None;
+        }
+        let index = self.state.offset() + (row - inner.y) as usize;
+        (index < self.items.len()).then_some(index)
+    }
 }
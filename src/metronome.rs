@@ -1,22 +1,54 @@
 /// This file houses the Metronome code which has the audio event loop for running the click
 /// It is started on a new thread by App and also shares state with it via Arc variables
+use crate::{
+    audio::{AudioBackend, AudioOutput, AudioSink},
+    clock::{Clock, RealClock},
+    processing::{LoadedSample, SoundProcessing},
+    song_map::SongMap,
+    sounds::{Sound, SoundSource},
+    timing::TimingLog,
+};
 use atomic_float::AtomicF64;
-use color_eyre::{eyre::eyre, Report, Result};
+use color_eyre::{Report, Result};
 use rodio::source::Source;
-use rodio::{Decoder, OutputStream, OutputStreamHandle};
 use std::{
-    fs::File,
-    io,
+    collections::{BTreeMap, VecDeque},
+    ops::RangeInclusive,
     sync::{
-        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
     },
-    thread,
     time::{Duration, Instant},
 };
 
+// How often to try opening the audio device again after it was lost
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// The latency offsets that can be set in ms, Bluetooth headphones can be a few hundred ms behind
+pub const LATENCY_RANGE: RangeInclusive<i64> = -500..=500;
+
 pub struct Metronome {
     pub settings: MetronomeSettings,
+    clock: Box<dyn Clock>,
+    refresh_rate: Duration,
+    output: Option<Box<dyn AudioSink>>,
+    running: bool,
+    last_refresh: Instant,
+    // Metronome first / last tick used for timing beats. Ticks are due on a grid of grid_delay steps from grid_start,
+    // so a late tick doesn't push back the ones after it. The grid starts again when the metronome starts and from
+    // the last tick when the tempo changes
+    first_tick: bool,
+    last_tick: Instant,
+    grid_start: Instant,
+    grid_delay: Duration,
+    grid_ticks: u32,
+    // When the device is lost the beats keep being counted silently so the metronome stays in time, and once it is
+    // back the click waits for the top of the next bar. While playing on the default device instead, the chosen one is
+    // still tried and switched back to at the top of the bar after it is found
+    reconnect_at: Option<Instant>,
+    wait_for_bar: bool,
+    next_output: Option<Box<dyn AudioSink>>,
+    // Beat and bar counts waiting to be shown, with when to show them
+    visual_queue: VecDeque<(Instant, u64, u64)>,
 }
 
 // These settings are also shared with an instance of App to update the metronome after it has been
@@ -33,13 +65,34 @@ pub struct Metronome {
 // beats_per_bar        : number of beats played by the metronome per bar (ie. 6 beats in a 4/4 triplets bar)
 // bar_count            : the number of bars elapsed since starting the metronome
 // is_running           : whether or not the metronome is running
+// muted                : keep counting and showing the beat without playing the click
 // volume               : volume of the metronome sound
-// sound_list           : vector of strings of selectable sounds (from the /assets folder)
+// sound_list           : vector of selectable sounds (sound files, embedded samples and synth voices)
 // selected_sound       : index in the sound_list of the selected sound
+// synth_pitch          : pitch of the synth voices in semitones away from their base pitch
+// synth_decay          : decay time of the synth voices in milliseconds
+// sound_processing     : trimming, normalizing and pitch of each sample by sound name, saved in the config file
+// sample_cache         : the selected sound's samples, decoded and processed so they are only loaded once
+// audio_backend        : what the click is played on, the audio device or (for testing) nothing or a capture
+// audio_output         : the host and device the click is played on
+// reopen_output        : set by App to open audio_output again (ie when a different device is chosen)
+// audio_error          : why the audio device couldn't be opened, the metronome stays paused until it is fixed
+// audio_warning        : set while the audio device is lost and being reconnected, or when it fell back to the default
+// latency_offset       : how many ms the visual beat is shown after the click is played, negative shows it before
+// visual_beat_count    : current_beat_count as it is shown, which follows it by latency_offset
+// visual_bar_count     : bar_count as it is shown
+// last_tick_time       : when the last tick was played, None while stopped
 // tick_count           : the current tick count for the refresh rate
+// tick_timings         : when each tick was scheduled and when it played, recorded in debug mode to measure jitter
 // debug                : enable debugging mode
 // error                : used to report errors to the front end
+// count_in             : play a bar of count-in (bar 0) when the metronome is restarted
+// restart              : set by App to restart the metronome from the top of the bar (ie when changing songs)
+// song_map             : optional song structure the metronome follows bar by bar
+// current_section      : index of the song map section currently playing
+// accents              : bit mask of the accented beats in the bar (bit 0 is the first beat)
 //
+#[derive(Clone)]
 pub struct MetronomeSettings {
     pub bpm: Arc<AtomicU64>,
     pub ns_delay: Arc<AtomicU64>,
@@ -52,12 +105,32 @@ pub struct MetronomeSettings {
     pub beats_per_bar: Arc<AtomicU64>,
     pub bar_count: Arc<AtomicU64>,
     pub is_running: Arc<AtomicBool>,
+    pub muted: Arc<AtomicBool>,
     pub volume: Arc<AtomicF64>,
-    pub sound_list: Vec<String>,
+    pub sound_list: Arc<RwLock<Vec<Sound>>>,
     pub selected_sound: Arc<AtomicUsize>,
+    pub synth_pitch: Arc<AtomicF64>,
+    pub synth_decay: Arc<AtomicF64>,
+    pub sound_processing: Arc<RwLock<BTreeMap<String, SoundProcessing>>>,
+    pub sample_cache: Arc<Mutex<Vec<CachedSample>>>,
+    pub audio_backend: Arc<RwLock<AudioBackend>>,
+    pub audio_output: Arc<RwLock<AudioOutput>>,
+    pub reopen_output: Arc<AtomicBool>,
+    pub audio_error: Arc<RwLock<Option<String>>>,
+    pub audio_warning: Arc<RwLock<Option<String>>>,
+    pub latency_offset: Arc<AtomicI64>,
+    pub visual_beat_count: Arc<AtomicU64>,
+    pub visual_bar_count: Arc<AtomicU64>,
+    pub last_tick_time: Arc<RwLock<Option<Instant>>>,
     pub tick_count: Arc<AtomicU64>,
+    pub tick_timings: Arc<Mutex<TimingLog>>,
     pub debug: Arc<AtomicBool>,
     pub error: Arc<AtomicBool>,
+    pub count_in: Arc<AtomicBool>,
+    pub restart: Arc<AtomicBool>,
+    pub song_map: Arc<RwLock<Option<SongMap>>>,
+    pub current_section: Arc<AtomicUsize>,
+    pub accents: Arc<AtomicU64>,
 }
 
 // This interface is used to set up the metronome without having to initialize internal variables
@@ -69,106 +142,309 @@ pub struct InitMetronomeSettings {
     pub volume: f64,
     pub debug: bool,
     pub is_running: bool,
+    pub count_in: bool,
+    pub ts_triplets: bool,
+    pub sub_eights: bool,
+    pub sub_sixteens: bool,
+    pub synth_pitch: f64,
+    pub synth_decay: f64,
+}
+
+// A sample as it was loaded for one kind of click, kept until the sound or its processing changes
+pub struct CachedSample {
+    sound: Sound,
+    kind: TickKind,
+    processing: SoundProcessing,
+    sample: LoadedSample,
+}
+
+// The role of a single click within the bar. Accents fall on accented beats, subdivisions fall between beats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TickKind {
+    Accent,
+    Normal,
+    Subdivision,
 }
 
 impl Metronome {
     pub fn new(new_settings: &MetronomeSettings) -> Metronome {
+        Metronome::with_clock(new_settings, Box::new(RealClock))
+    }
+
+    // A metronome that takes its time from the given clock, ie a simulated one for testing
+    pub fn with_clock(new_settings: &MetronomeSettings, clock: Box<dyn Clock>) -> Metronome {
+        let now = clock.now();
         Metronome {
-            settings: MetronomeSettings {
-                bpm: Arc::clone(&new_settings.bpm),
-                ns_delay: Arc::clone(&new_settings.ns_delay),
-                ts_note: Arc::clone(&new_settings.ts_note),
-                ts_value: Arc::clone(&new_settings.ts_value),
-                ts_triplets: Arc::clone(&new_settings.ts_triplets),
-                sub_eights: Arc::clone(&new_settings.sub_eights),
-                sub_sixteens: Arc::clone(&new_settings.sub_sixteens),
-                current_beat_count: Arc::clone(&new_settings.current_beat_count),
-                beats_per_bar: Arc::clone(&new_settings.beats_per_bar),
-                bar_count: Arc::clone(&new_settings.bar_count),
-                is_running: Arc::clone(&new_settings.is_running),
-                volume: Arc::clone(&new_settings.volume),
-                sound_list: new_settings.sound_list.clone(),
-                selected_sound: Arc::clone(&new_settings.selected_sound),
-                tick_count: Arc::clone(&new_settings.tick_count),
-                debug: Arc::clone(&new_settings.debug),
-                error: Arc::clone(&new_settings.error),
-            },
+            clock,
+            refresh_rate: Duration::ZERO,
+            output: None,
+            running: false,
+            last_refresh: now,
+            first_tick: true,
+            last_tick: now,
+            grid_start: now,
+            grid_delay: Duration::ZERO,
+            grid_ticks: 0,
+            reconnect_at: None,
+            wait_for_bar: false,
+            next_output: None,
+            visual_queue: VecDeque::new(),
+            settings: new_settings.clone(),
         }
     }
 
     pub fn start(&mut self, refresh_rate: u64) {
-        let refresh_rate = Duration::from_nanos(refresh_rate);
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let mut running = self.settings.is_running.load(Ordering::Relaxed);
-        let mut last_refresh = Instant::now();
-
-        // Metronome first / last tick used for timing beats
-        let mut first_tick = true;
-        let mut last_tick = Instant::now();
-
-        loop {
-            let timeout_refresh = refresh_rate
-                .checked_sub(last_refresh.elapsed())
-                .unwrap_or(refresh_rate);
-
-            if running {
-                // Exit the loop if there was an error
-                if self.settings.error.load(Ordering::Relaxed) {
-                    return;
+        self.prepare(refresh_rate);
+        while self.step() {}
+    }
+
+    // Open the output and get ready to step, refresh_rate is the time in nanoseconds between steps
+    pub fn prepare(&mut self, refresh_rate: u64) {
+        self.refresh_rate = Duration::from_nanos(refresh_rate);
+        self.output = self.open_output();
+        self.running = self.settings.is_running.load(Ordering::Relaxed);
+        self.last_refresh = self.clock.now();
+        self.first_tick = true;
+        self.last_tick = self.clock.now();
+    }
+
+    // One pass of the metronome loop: play the next tick if it is due, then sleep until the next step. Returns false
+    // once the metronome should stop
+    pub fn step(&mut self) -> bool {
+        // Sleep out the rest of the refresh period, or a whole one if it is already over
+        let elapsed = self.clock.now().duration_since(self.last_refresh);
+        let timeout_refresh = match elapsed < self.refresh_rate {
+            true => self.refresh_rate - elapsed,
+            false => self.refresh_rate,
+        };
+
+        // Start over from the top of the bar, with a count-in bar (bar 0) if it is enabled
+        if self.settings.restart.swap(false, Ordering::Relaxed) {
+            let first_bar = if self.settings.count_in.load(Ordering::Relaxed) {
+                0
+            } else {
+                1
+            };
+            self.settings.bar_count.swap(first_bar, Ordering::Relaxed);
+            self.settings.current_beat_count.swap(0, Ordering::Relaxed);
+            self.first_tick = true;
+        }
+
+        self.check_output();
+        // Without a device there is nothing to click on, so stay paused until a working one is chosen
+        if self.running && self.output.is_none() && self.reconnect_at.is_none() {
+            self.settings.is_running.swap(false, Ordering::Relaxed);
+            self.running = false;
+        }
+
+        if self.running {
+            // Exit the loop if there was an error
+            if self.settings.error.load(Ordering::Relaxed) {
+                return false;
+            }
+            // Run the first tick if the metronome was just started
+            if self.first_tick {
+                self.first_tick = false;
+                if !self.follow_song_map() {
+                    self.settings.is_running.swap(false, Ordering::Relaxed);
+                    return true;
+                }
+                self.last_tick = self.clock.now();
+                self.grid_start = self.last_tick;
+                self.grid_delay =
+                    Duration::from_nanos(self.settings.ns_delay.load(Ordering::Relaxed));
+                self.grid_ticks = 0;
+                self.settings.tick_timings.lock().unwrap().start_run();
+                self.record_timing(self.last_tick);
+                self.play_tick();
+            } else {
+                let delay = Duration::from_nanos(self.settings.ns_delay.load(Ordering::Relaxed));
+                if delay != self.grid_delay {
+                    self.grid_start += self.grid_delay * self.grid_ticks;
+                    self.grid_delay = delay;
+                    self.grid_ticks = 0;
                 }
-                // Run the first tick if the metronome was just started
-                if first_tick {
-                    first_tick = false;
-                    self.start_tick_thread(stream_handle.clone());
-                    last_tick = Instant::now();
-                } else {
-                    let time_since_last_tick = Instant::now().duration_since(last_tick);
-                    let delay =
-                        Duration::from_nanos(self.settings.ns_delay.load(Ordering::Relaxed));
-                    if time_since_last_tick >= delay {
-                        last_tick = Instant::now();
-                        self.start_tick_thread(stream_handle.clone());
+                let scheduled = self.grid_start + delay * (self.grid_ticks + 1);
+                if self.clock.now() >= scheduled {
+                    self.last_tick = self.clock.now();
+                    self.grid_ticks += 1;
+                    self.record_timing(scheduled);
+                    self.play_tick();
+                }
+            }
+        }
+
+        self.show_due_beats();
+
+        self.running = self.settings.is_running.load(Ordering::Relaxed);
+        if !self.running {
+            self.settings.bar_count.swap(1, Ordering::Relaxed);
+            self.settings.current_beat_count.swap(0, Ordering::Relaxed);
+            self.first_tick = true;
+            self.visual_queue.clear();
+            self.settings.visual_bar_count.swap(1, Ordering::Relaxed);
+            self.settings.visual_beat_count.swap(0, Ordering::Relaxed);
+            *self.settings.last_tick_time.write().unwrap() = None;
+        }
+        // We always sleep for the tick duration regardless if the metronome is running
+        self.clock.sleep(timeout_refresh);
+
+        // Perform debug functionality
+        if self.settings.debug.load(Ordering::Relaxed)
+            && self.clock.now().duration_since(self.last_refresh) >= self.refresh_rate
+        {
+            let current_tick_count = self.settings.tick_count.load(Ordering::Relaxed);
+            let result = current_tick_count.checked_add(1).unwrap_or(0);
+            self.settings.tick_count.swap(result, Ordering::Relaxed);
+            self.last_refresh = self.clock.now();
+        }
+        true
+    }
+
+    // Open a newly chosen device, and notice when the current one fails and reconnect to it
+    fn check_output(&mut self) {
+        if self.settings.reopen_output.swap(false, Ordering::Relaxed) {
+            drop(self.output.take()); // close the old device first, some can only be opened once
+            *self.settings.audio_warning.write().unwrap() = None;
+            self.reconnect_at = None;
+            self.next_output = None;
+            self.output = self.open_output();
+        }
+        if let Some(error) = self.output.as_ref().and_then(|output| output.error()) {
+            drop(self.output.take());
+            *self.settings.audio_warning.write().unwrap() =
+                Some("Lost the audio device (".to_owned() + &error + "), reconnecting...");
+            self.reconnect_at = Some(self.clock.now() + RECONNECT_INTERVAL);
+        }
+        if let Some(time) = self.reconnect_at {
+            if self.clock.now() >= time {
+                self.reconnect_at = None;
+                let choice = self.settings.audio_output.read().unwrap().clone();
+                match self.settings.open_audio_sink(&choice) {
+                    // playing on the default device, it carries on until the top of the bar
+                    Ok(output) if self.output.is_some() => self.next_output = Some(output),
+                    Ok(output) => {
+                        *self.settings.audio_warning.write().unwrap() = None;
+                        self.output = Some(output);
+                        self.wait_for_bar = true;
+                    }
+                    Err(_) => {
+                        if self.output.is_none() {
+                            self.output = self.fall_back_output(&choice);
+                            self.wait_for_bar = self.output.is_some();
+                        }
+                        self.reconnect_at = Some(self.clock.now() + RECONNECT_INTERVAL);
                     }
                 }
             }
+        }
+    }
 
-            running = self.settings.is_running.load(Ordering::Relaxed);
-            if !running {
-                self.settings.bar_count.swap(1, Ordering::Relaxed);
-                self.settings.current_beat_count.swap(0, Ordering::Relaxed);
-                first_tick = true;
+    // Open the chosen audio device, telling App why if it can't be used
+    fn open_output(&self) -> Option<Box<dyn AudioSink>> {
+        let choice = self.settings.audio_output.read().unwrap().clone();
+        match self.settings.open_audio_sink(&choice) {
+            Ok(output) => {
+                *self.settings.audio_error.write().unwrap() = None;
+                Some(output)
             }
-            // We always sleep for the tick duration regardless if the metronome is running
-            spin_sleep::sleep(timeout_refresh);
-
-            // Perform debug functionality
-            if self.settings.debug.load(Ordering::Relaxed) && last_refresh.elapsed() >= refresh_rate
-            {
-                let current_tick_count = self.settings.tick_count.load(Ordering::Relaxed);
-                let result = current_tick_count.checked_add(1).unwrap_or(0);
-                self.settings.tick_count.swap(result, Ordering::Relaxed);
-                last_refresh = Instant::now();
+            Err(error) => {
+                *self.settings.audio_error.write().unwrap() = Some(error.to_string());
+                None
             }
         }
     }
 
-    // Load the tick function into a new thread for execution (that way this isn't tied to bpm anymore)
-    fn start_tick_thread(&mut self, stream_handle: OutputStreamHandle) {
-        let selected_sound_name =
-            self.settings.sound_list[self.settings.selected_sound.load(Ordering::Relaxed)].clone();
-        let volume = self.settings.volume.load(Ordering::Relaxed);
-        let error = self.settings.error.clone();
-        let handler = thread::spawn(move || {
-            match metronome_tick(stream_handle, selected_sound_name, volume) {
-                Ok(_) => {}
-                Err(_) => {
-                    error.swap(true, Ordering::Relaxed);
-                }
+    // Play on the default device while the chosen device is missing
+    fn fall_back_output(&self, choice: &AudioOutput) -> Option<Box<dyn AudioSink>> {
+        if *choice == AudioOutput::default() {
+            return None;
+        }
+        let output = self
+            .settings
+            .open_audio_sink(&AudioOutput::default())
+            .ok()?;
+        *self.settings.audio_warning.write().unwrap() =
+            Some(choice.label() + " is missing, playing on the default device");
+        Some(output)
+    }
+
+    // Play the next tick and count it. Playing only hands the sound to the output's mixer so it doesn't hold up the
+    // timing loop. The tick is only counted while muted, there is no output or the click is waiting for the next bar
+    fn play_tick(&mut self) {
+        self.wait_for_bar &= !self.is_downbeat();
+        if self.is_downbeat() && self.next_output.is_some() {
+            self.output = self.next_output.take();
+            *self.settings.audio_warning.write().unwrap() = None;
+        }
+        let output = match self.wait_for_bar || self.settings.muted.load(Ordering::Relaxed) {
+            true => None,
+            false => self.output.as_deref(),
+        };
+        // A positive latency offset holds back the visual beat, a negative one holds back the click instead
+        let offset = self.settings.latency_offset.load(Ordering::Relaxed);
+        let visual_delay = Duration::from_millis(offset.max(0) as u64);
+        let audio_delay = Duration::from_millis(offset.min(0).unsigned_abs());
+        if let Some(output) = output {
+            let kind = self.next_tick_kind();
+            let source = self.settings.open_selected_sound(kind);
+            let gain = tick_gain(self.settings.volume.load(Ordering::Relaxed));
+            if metronome_tick(output, source, gain, audio_delay).is_err() {
+                self.settings.error.swap(true, Ordering::Relaxed);
             }
-        });
-        // close the thread to prevent multiples from spawning
-        let _ = handler.join();
+        }
         self.beat_count();
+        *self.settings.last_tick_time.write().unwrap() = Some(self.last_tick);
+        self.visual_queue.push_back((
+            self.last_tick + visual_delay,
+            self.settings.current_beat_count.load(Ordering::Relaxed),
+            self.settings.bar_count.load(Ordering::Relaxed),
+        ));
+    }
+
+    // Show the beat and bar counts whose time has come
+    fn show_due_beats(&mut self) {
+        let now = self.clock.now();
+        while let Some(&(time, beat, bar)) = self.visual_queue.front() {
+            if time > now {
+                break;
+            }
+            self.settings
+                .visual_beat_count
+                .swap(beat, Ordering::Relaxed);
+            self.settings.visual_bar_count.swap(bar, Ordering::Relaxed);
+            self.visual_queue.pop_front();
+        }
+    }
+
+    // Keep when the tick about to play was due and when it is actually playing, only while debugging
+    fn record_timing(&self, scheduled: Instant) {
+        if self.settings.debug.load(Ordering::Relaxed) {
+            self.settings
+                .tick_timings
+                .lock()
+                .unwrap()
+                .record(scheduled, self.last_tick);
+        }
+    }
+
+    // Whether the tick about to play is the first of the bar. u64::is_multiple_of would need Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn is_downbeat(&self) -> bool {
+        let beats_per_bar = self.settings.beats_per_bar.load(Ordering::Relaxed);
+        self.settings.current_beat_count.load(Ordering::Relaxed) % beats_per_bar == 0
+    }
+
+    // Work out whether the tick about to play is an accent, a normal beat or a subdivision
+    fn next_tick_kind(&self) -> TickKind {
+        let beats_per_bar = self.settings.beats_per_bar.load(Ordering::Relaxed);
+        let tick = self.settings.current_beat_count.load(Ordering::Relaxed) % beats_per_bar;
+        tick_kind(
+            tick,
+            self.settings.ts_note.load(Ordering::Relaxed),
+            beats_per_bar,
+            self.settings.accents.load(Ordering::Relaxed),
+        )
     }
 
     // Counts the number of beats and updates bar_count
@@ -180,6 +456,11 @@ impl Metronome {
             self.settings
                 .bar_count
                 .swap(new_bar_count, Ordering::Relaxed);
+            // The downbeat of the new bar has just played, so the next beat uses the new section's tempo and meter.
+            // Once the song map runs out we stop on that final downbeat
+            if !self.follow_song_map() {
+                self.settings.is_running.swap(false, Ordering::Relaxed);
+            }
         } else {
             current_beat_count += 1;
             self.settings
@@ -187,24 +468,557 @@ impl Metronome {
                 .swap(current_beat_count, Ordering::Relaxed);
         }
     }
+
+    // Switch tempo and meter to the song map section the current bar falls in (if a song map is loaded).
+    // Returns false once the song is over
+    fn follow_song_map(&self) -> bool {
+        let song_map = self.settings.song_map.read().unwrap();
+        if let Some(map) = song_map.as_ref() {
+            let bar = self.settings.bar_count.load(Ordering::Relaxed);
+            match map.section_at_bar(bar) {
+                Some((index, section)) => {
+                    self.settings.current_section.swap(index, Ordering::Relaxed);
+                    self.settings.set_tempo_and_meter(
+                        section.bpm,
+                        section.ts_note,
+                        section.ts_value,
+                    );
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+impl MetronomeSettings {
+    // Change the tempo and time signature together and recalculate the beat timing to match. Song map tempos can be
+    // fractional (ie imported from MIDI), the bpm shown is rounded but the beat timing uses the exact tempo
+    pub fn set_tempo_and_meter(&self, bpm: f64, ts_note: u64, ts_value: u64) {
+        self.bpm.swap(bpm.round() as u64, Ordering::Relaxed);
+        self.ts_note.swap(ts_note, Ordering::Relaxed);
+        self.ts_value.swap(ts_value, Ordering::Relaxed);
+        self.beats_per_bar
+            .swap(self.get_beats_per_bar(), Ordering::Relaxed);
+        self.ns_delay
+            .swap(self.ns_for_bpm(bpm, ts_value), Ordering::Relaxed);
+    }
+
+    // Open an output on the chosen backend, used by the metronome and sound previews
+    pub fn open_audio_sink(&self, output: &AudioOutput) -> Result<Box<dyn AudioSink>, Report> {
+        self.audio_backend.read().unwrap().open(output)
+    }
+
+    // Open the selected sound for a kind of click, used by both live playback and offline rendering
+    pub fn open_selected_sound(&self, kind: TickKind) -> Result<SoundSource, Report> {
+        let sound =
+            self.sound_list.read().unwrap()[self.selected_sound.load(Ordering::Relaxed)].clone();
+        self.open_sound(&sound, kind)
+    }
+
+    // Open a sound for a kind of click. Synth voices use the current synth settings, samples are decoded and
+    // processed the first time they are played and then replayed from memory
+    pub fn open_sound(&self, sound: &Sound, kind: TickKind) -> Result<SoundSource, Report> {
+        let pitch = self.synth_pitch.load(Ordering::Relaxed);
+        let decay = self.synth_decay.load(Ordering::Relaxed);
+        if let Sound::Synth(_) = sound {
+            return sound.open(kind, pitch, decay);
+        }
+        let processing = self.get_sound_processing(&sound.name());
+        let mut cache = self.sample_cache.lock().unwrap();
+        let cached = cache.iter().find(|cached| {
+            cached.sound == *sound && cached.kind == kind && cached.processing == processing
+        });
+        if let Some(cached) = cached {
+            return Ok(cached.sample.to_source());
+        }
+        let sample = LoadedSample::from_source(sound.open(kind, pitch, decay)?).process(processing);
+        let source = sample.to_source();
+        // only keep the other kinds of click of the same sound
+        cache.retain(|cached| cached.sound == *sound && cached.processing == processing);
+        cache.push(CachedSample {
+            sound: sound.clone(),
+            kind,
+            processing,
+            sample,
+        });
+        Ok(source)
+    }
+
+    // The processing for a sound, sounds that haven't been changed are played as they are
+    pub fn get_sound_processing(&self, name: &str) -> SoundProcessing {
+        self.sound_processing
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Convert a bpm value to the nanosecond delay (1/4 notes)
+    pub fn get_ns_from_bpm(&self) -> u64 {
+        (60_000_000_000.0_f64 / self.bpm.load(Ordering::Relaxed) as f64).round() as u64
+    }
+
+    // Take the current nanosecond delay and divide it based on the value note in the time signature
+    pub fn get_ns_for_note_value(&self) -> u64 {
+        self.ns_for_note_value(
+            self.get_ns_from_bpm(),
+            self.ts_value.load(Ordering::Relaxed),
+        )
+    }
+
+    // The nanosecond delay at a bpm and note value with the current subdivision, without changing what is playing
+    pub fn ns_for_bpm(&self, bpm: f64, value: u64) -> u64 {
+        self.ns_for_note_value((60_000_000_000.0_f64 / bpm).round() as u64, value)
+    }
+
+    // The nanosecond delay for the length of a bpm beat and a note value, with the current subdivision
+    fn ns_for_note_value(&self, quarter_ns_delay: u64, value: u64) -> u64 {
+        let mut current_ns_delay =
+            (quarter_ns_delay as f64 / note_value_divisor(value)).round() as u64;
+
+        // Calculate 8ths or 16ths subdivision in 4/4
+        if value == 4 {
+            if self.sub_eights.load(Ordering::Relaxed) {
+                current_ns_delay = (current_ns_delay as f64 / 2_f64).round() as u64;
+            } else if self.sub_sixteens.load(Ordering::Relaxed) {
+                current_ns_delay = (current_ns_delay as f64 / 4_f64).round() as u64;
+            }
+            // This was helpful in thinking about triplet calculation:
+            // https://math.stackexchange.com/questions/2646908/calculating-delay-time-in-milliseconds
+            if self.ts_triplets.load(Ordering::Relaxed) {
+                current_ns_delay = (current_ns_delay as f64 / 3_f64 * 2_f64).round() as u64;
+            }
+        }
+
+        current_ns_delay
+    }
+
+    // Calculate and return the number of metronome beats per bar (based on time signature and subdivision)
+    pub fn get_beats_per_bar(&self) -> u64 {
+        self.beats_per_bar_for(self.ts_note.load(Ordering::Relaxed))
+    }
+
+    // The number of metronome beats in a bar of ts_note beats with the current subdivision
+    pub fn beats_per_bar_for(&self, ts_note: u64) -> u64 {
+        let mut num_ticks = ts_note;
+        if self.ts_triplets.load(Ordering::Relaxed) {
+            num_ticks = (num_ticks as f64 * 1.5_f64).round() as u64;
+        }
+        if self.sub_eights.load(Ordering::Relaxed) {
+            num_ticks *= 2;
+        } else if self.sub_sixteens.load(Ordering::Relaxed) {
+            num_ticks *= 4;
+        }
+        num_ticks
+    }
+}
+
+// The gain to play a click at, shared by live playback and offline rendering so they always sound the same. Every kind
+// of click plays at the volume setting, accents and subdivisions stand out through the sound set's samples
+pub fn tick_gain(volume: f64) -> f32 {
+    (volume / 100.0) as f32
+}
+
+// Work out what kind of click a tick (0 based index within the bar) is. A tick lands on a beat when it lines up with
+// one of the ts_note beats of the bar, so this also works for triplets where not every beat gets a click
+#[allow(clippy::manual_is_multiple_of)] // u64::is_multiple_of would need Rust 1.87
+pub fn tick_kind(tick: u64, ts_note: u64, beats_per_bar: u64, accents: u64) -> TickKind {
+    if (tick * ts_note) % beats_per_bar != 0 {
+        return TickKind::Subdivision;
+    }
+    let beat = tick * ts_note / beats_per_bar;
+    if beat < 64 && accents & (1 << beat) != 0 {
+        TickKind::Accent
+    } else {
+        TickKind::Normal
+    }
+}
+
+// How many beats of the time signature's note value fit in one bpm beat. Eighth note meters like 12/8 are treated as
+// triplet meters, so the bpm counts dotted quarter notes and there are three eighths to the beat
+pub fn note_value_divisor(ts_value: u64) -> f64 {
+    match ts_value {
+        8 => 3.0,
+        16 => 4.0,
+        32 => 8.0,
+        64 => 16.0,
+        _ => 1.0,
+    }
 }
 
 fn metronome_tick(
-    stream_handle: OutputStreamHandle,
-    selected_sound_name: String,
-    volume: f64,
+    output: &dyn AudioSink,
+    source: Result<SoundSource, Report>,
+    gain: f32,
+    delay: Duration,
 ) -> Result<(), Report> {
-    // TODO: Don't load the sample every time, if possible load once and replay.
-    let file = io::BufReader::new(
-        match File::open("./assets/".to_owned() + &selected_sound_name) {
-            Ok(value) => value,
-            Err(_) => {
-                return Err(eyre!("Error: Problem loading sound"));
+    output.play(Box::new(source?.amplify(gain).delay(delay)));
+    Ok(())
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::App, audio::CaptureSink, clock::SimulatedClock, song_map::SongMap};
+
+    const TEST_SETTINGS: InitMetronomeSettings = InitMetronomeSettings {
+        bpm: 120,
+        ts_note: 4,
+        ts_value: 4,
+        volume: 100.0,
+        is_running: false,
+        debug: false,
+        count_in: false,
+        ts_triplets: false,
+        sub_eights: false,
+        sub_sixteens: false,
+        synth_pitch: 0.0,
+        synth_decay: 40.0,
+    };
+
+    // Run the metronome on a simulated clock until it has played the given number of ticks (or stopped), returning
+    // the beat and bar count after each tick along with the tick's time in ms from the first tick
+    fn simulate(test_app: &App, ticks: usize) -> Vec<(u64, u64, u128)> {
+        let clock = SimulatedClock::new();
+        let capture = CaptureSink::with_clock(Arc::new(clock.clone()));
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(capture.clone());
+        test_app.settings.is_running.swap(true, Ordering::Relaxed);
+        let mut metronome = Metronome::with_clock(&test_app.settings, Box::new(clock.clone()));
+        metronome.prepare(1_000_000);
+        let start = clock.now();
+        let mut counts = Vec::new();
+        while counts.len() < ticks && metronome.running {
+            metronome.step();
+            if capture.ticks().len() > counts.len() {
+                counts.push((
+                    metronome
+                        .settings
+                        .current_beat_count
+                        .load(Ordering::Relaxed),
+                    metronome.settings.bar_count.load(Ordering::Relaxed),
+                    0,
+                ));
             }
-        },
-    );
+            assert!(clock.now().duration_since(start) < Duration::from_secs(60));
+        }
+        for (count, tick) in counts.iter_mut().zip(capture.ticks()) {
+            count.2 = tick.time.duration_since(start).as_millis();
+        }
+        counts
+    }
 
-    let source = Decoder::new(file).unwrap();
-    let _ = stream_handle.play_raw(source.amplify((volume / 100.0) as f32).convert_samples());
-    Ok(())
+    // An app with the synth sounds loaded and the beat timing worked out, like App::init without the thread
+    fn test_app(settings: InitMetronomeSettings) -> App {
+        let mut test_app = App::new(settings, 7);
+        test_app.sound_dirs.clear();
+        test_app.populate_sounds().unwrap();
+        test_app.settings.set_tempo_and_meter(
+            settings.bpm as f64,
+            settings.ts_note,
+            settings.ts_value,
+        );
+        test_app
+    }
+
+    // quarter notes at 120bpm should land exactly every 500ms, counting beats 1 to 4 and then moving to the next bar
+    #[test]
+    fn metronome_quarter_notes() {
+        let counts = simulate(&test_app(TEST_SETTINGS), 9);
+        assert_eq!(
+            counts,
+            vec![
+                (1, 1, 0),
+                (2, 1, 500),
+                (3, 1, 1000),
+                (4, 1, 1500),
+                (1, 2, 2000),
+                (2, 2, 2500),
+                (3, 2, 3000),
+                (4, 2, 3500),
+                (1, 3, 4000),
+            ]
+        );
+    }
+
+    // eighths double the ticks in the bar and triplets in 4/4 give 6 ticks a bar, two thirds of a beat apart. A tick
+    // that isn't a whole number of refreshes long plays on the first refresh after it is due, without the ticks after
+    // it falling further behind
+    #[test]
+    fn metronome_subdivisions() {
+        let settings = InitMetronomeSettings {
+            sub_eights: true,
+            ..TEST_SETTINGS
+        };
+        let counts = simulate(&test_app(settings), 10);
+        let beats: Vec<u64> = counts.iter().map(|count| count.0).collect();
+        assert_eq!(beats, vec![1, 2, 3, 4, 5, 6, 7, 8, 1, 2]);
+        assert_eq!(counts[8].1, 2);
+        for (index, count) in counts.iter().enumerate() {
+            assert_eq!(count.2, index as u128 * 250);
+        }
+
+        let settings = InitMetronomeSettings {
+            bpm: 60,
+            ts_triplets: true,
+            ..TEST_SETTINGS
+        };
+        let test_app = test_app(settings);
+        let delay = test_app.settings.ns_delay.load(Ordering::Relaxed) as u128;
+        let counts = simulate(&test_app, 8);
+        let beats: Vec<u64> = counts.iter().map(|count| count.0).collect();
+        assert_eq!(beats, vec![1, 2, 3, 4, 5, 6, 1, 2]);
+        assert_eq!(counts[6].1, 2);
+        for (index, count) in counts.iter().enumerate() {
+            assert_eq!(count.2, (index as u128 * delay).div_ceil(1_000_000));
+        }
+    }
+
+    // in debug mode each tick should record how late it played against where it falls in time from the first tick,
+    // here triplets land up to a refresh after they are due. Each start is numbered as a new run
+    #[test]
+    fn metronome_records_jitter() {
+        let settings = InitMetronomeSettings {
+            bpm: 60,
+            ts_triplets: true,
+            ..TEST_SETTINGS
+        };
+        let test_app = test_app(settings);
+        simulate(&test_app, 4);
+        assert!(test_app
+            .settings
+            .tick_timings
+            .lock()
+            .unwrap()
+            .stats()
+            .is_none());
+
+        test_app.settings.debug.swap(true, Ordering::Relaxed);
+        simulate(&test_app, 3);
+        simulate(&test_app, 5);
+        let tick_timings = test_app.settings.tick_timings.lock().unwrap();
+        let runs: Vec<u32> = tick_timings
+            .ticks()
+            .iter()
+            .map(|timing| timing.run)
+            .collect();
+        assert_eq!(runs, vec![2, 2, 2, 3, 3, 3, 3, 3]);
+        let jitter: Vec<f64> = tick_timings.ticks()[3..]
+            .iter()
+            .map(|timing| (timing.jitter_ms() * 1000.0).round() / 1000.0)
+            .collect();
+        assert_eq!(jitter, vec![0.0, 0.333, 0.667, 1.0, 0.333]);
+        let stats = tick_timings.stats().unwrap();
+        assert_eq!(stats.count, 8);
+        assert!((stats.max - 1.0).abs() < 0.001);
+    }
+
+    // a positive latency offset should hold back the shown beat, a negative one should hold back the click
+    #[test]
+    fn metronome_latency_offset() {
+        let test_app = test_app(TEST_SETTINGS);
+        test_app.settings.latency_offset.swap(40, Ordering::Relaxed);
+        let clock = SimulatedClock::new();
+        let capture = CaptureSink::with_clock(Arc::new(clock.clone()));
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(capture.clone());
+        test_app.settings.is_running.swap(true, Ordering::Relaxed);
+        let mut metronome = Metronome::with_clock(&test_app.settings, Box::new(clock.clone()));
+        metronome.prepare(1_000_000);
+        let start = clock.now();
+        let visual_beat = || test_app.settings.visual_beat_count.load(Ordering::Relaxed);
+        metronome.step();
+        assert_eq!(capture.ticks().len(), 1);
+        assert_eq!(visual_beat(), 0);
+        let shown = loop {
+            let now = clock.now();
+            metronome.step();
+            if visual_beat() != 0 {
+                break now;
+            }
+        };
+        assert_eq!(shown.duration_since(start), Duration::from_millis(40));
+        assert_eq!(
+            *test_app.settings.last_tick_time.read().unwrap(),
+            Some(start)
+        );
+
+        // a click held back by 20ms starts with 20ms of silence
+        test_app
+            .settings
+            .latency_offset
+            .swap(-20, Ordering::Relaxed);
+        while capture.ticks().len() < 2 {
+            metronome.step();
+        }
+        assert_eq!(visual_beat(), 2);
+        let tick = &capture.ticks()[1];
+        let silence = tick.samples.iter().position(|value| *value != 0.0).unwrap();
+        let silence_frames = silence / tick.channels as usize;
+        assert!(silence_frames >= tick.sample_rate as usize / 50);
+
+        // stopping resets the shown beat
+        test_app.settings.is_running.swap(false, Ordering::Relaxed);
+        metronome.step();
+        assert_eq!(visual_beat(), 0);
+        assert!(test_app.settings.last_tick_time.read().unwrap().is_none());
+    }
+
+    // muting should silence the click while the beats carry on being counted and shown
+    #[test]
+    fn metronome_muted() {
+        let test_app = test_app(TEST_SETTINGS);
+        test_app.settings.muted.swap(true, Ordering::Relaxed);
+        let clock = SimulatedClock::new();
+        let capture = CaptureSink::with_clock(Arc::new(clock.clone()));
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(capture.clone());
+        test_app.settings.is_running.swap(true, Ordering::Relaxed);
+        let mut metronome = Metronome::with_clock(&test_app.settings, Box::new(clock.clone()));
+        metronome.prepare(1_000_000);
+        for _ in 0..2_001 {
+            metronome.step();
+        }
+        assert!(capture.ticks().is_empty());
+        assert!(test_app.settings.is_running.load(Ordering::Relaxed));
+        assert_eq!(test_app.settings.bar_count.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            test_app.settings.current_beat_count.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            test_app.settings.visual_beat_count.load(Ordering::Relaxed),
+            1
+        );
+
+        // unmuting brings the click straight back
+        test_app.settings.muted.swap(false, Ordering::Relaxed);
+        for _ in 0..500 {
+            metronome.step();
+        }
+        assert_eq!(capture.ticks().len(), 1);
+        assert_eq!(
+            test_app.settings.current_beat_count.load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    // changing the bpm while playing should take effect from the next tick
+    #[test]
+    fn metronome_tempo_change() {
+        let test_app = test_app(TEST_SETTINGS);
+        let clock = SimulatedClock::new();
+        let capture = CaptureSink::with_clock(Arc::new(clock.clone()));
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(capture.clone());
+        test_app.settings.is_running.swap(true, Ordering::Relaxed);
+        let mut metronome = Metronome::with_clock(&test_app.settings, Box::new(clock.clone()));
+        metronome.prepare(1_000_000);
+        let start = clock.now();
+        while capture.ticks().len() < 2 {
+            metronome.step();
+        }
+        test_app.settings.set_tempo_and_meter(60.0, 4, 4);
+        while capture.ticks().len() < 4 {
+            metronome.step();
+        }
+        let times: Vec<Duration> = capture
+            .ticks()
+            .iter()
+            .map(|tick| tick.time.duration_since(start))
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(500),
+                Duration::from_millis(1_500),
+                Duration::from_millis(2_500),
+            ]
+        );
+        assert_eq!(
+            metronome
+                .settings
+                .current_beat_count
+                .load(Ordering::Relaxed),
+            4
+        );
+    }
+
+    // a song map switches tempo and meter on the downbeat of each section, and stops on the downbeat after the song
+    // is over, going back to the top
+    #[test]
+    fn metronome_song_map() {
+        let test_app = test_app(TEST_SETTINGS);
+        *test_app.settings.song_map.write().unwrap() =
+            Some(SongMap::parse("1 x 2/4 @120\n1 x 3/4 @60").unwrap());
+        let counts = simulate(&test_app, 10);
+        assert_eq!(
+            counts,
+            vec![
+                (1, 1, 0),
+                (2, 1, 500),
+                (1, 2, 1000),
+                (2, 2, 2000),
+                (3, 2, 3000),
+                (0, 1, 4000),
+            ]
+        );
+        assert!(!test_app.settings.is_running.load(Ordering::Relaxed));
+    }
+
+    // while the audio device is lost the beats are still counted, and once it is back the click waits for a downbeat
+    #[test]
+    fn metronome_counts_without_output() {
+        let test_app = test_app(TEST_SETTINGS);
+        let mut metronome = Metronome::new(&test_app.settings);
+        assert!(metronome.is_downbeat());
+        for _ in 0..6 {
+            metronome.play_tick();
+        }
+        assert_eq!(metronome.settings.bar_count.load(Ordering::Relaxed), 2);
+        assert!(!metronome.is_downbeat());
+
+        let capture = CaptureSink::default();
+        metronome.output = Some(Box::new(capture.clone()));
+        metronome.wait_for_bar = true;
+        metronome.play_tick();
+        metronome.play_tick();
+        assert!(capture.ticks().is_empty());
+        assert!(metronome.is_downbeat());
+        metronome.play_tick();
+        metronome.play_tick();
+        assert_eq!(capture.ticks().len(), 2);
+    }
+
+    // while playing on the default device the chosen one is tried again, and played on from the next bar once it is
+    // back
+    #[test]
+    fn metronome_returns_from_fallback() {
+        let test_app = test_app(TEST_SETTINGS);
+        let clock = SimulatedClock::new();
+        let chosen = CaptureSink::with_clock(Arc::new(clock.clone()));
+        let fallback = CaptureSink::with_clock(Arc::new(clock.clone()));
+        *test_app.settings.audio_backend.write().unwrap() = AudioBackend::Capture(chosen.clone());
+        let mut metronome = Metronome::with_clock(&test_app.settings, Box::new(clock.clone()));
+        metronome.output = Some(Box::new(fallback.clone()));
+        metronome.reconnect_at = Some(clock.now() + RECONNECT_INTERVAL);
+        *metronome.settings.audio_warning.write().unwrap() =
+            Some("playing on the default".to_string());
+        metronome.play_tick();
+        metronome.play_tick();
+
+        clock.sleep(RECONNECT_INTERVAL);
+        metronome.check_output();
+        assert!(metronome.reconnect_at.is_none());
+        metronome.play_tick();
+        metronome.play_tick();
+        assert_eq!(fallback.ticks().len(), 4);
+        assert!(chosen.ticks().is_empty());
+        assert!(metronome.settings.audio_warning.read().unwrap().is_some());
+
+        metronome.play_tick();
+        assert_eq!(fallback.ticks().len(), 4);
+        assert_eq!(chosen.ticks().len(), 1);
+        assert!(metronome.settings.audio_warning.read().unwrap().is_none());
+    }
 }
@@ -0,0 +1,537 @@
+/// Midi.rs reads and writes Standard MIDI Files so that the metronome can follow the tempo and meter of an arrangement
+/// made in a DAW, and the click can be dropped back into one. Tempo (FF 51) and time signature (FF 58) meta events map
+/// to song map sections, markers (FF 06) name them.
+// midly docs: https://docs.rs/midly/latest/midly/
+use crate::{
+    click_track::Bar,
+    metronome::{note_value_divisor, TickKind},
+    song_map::{Section, SongMap, MAX_SECTION_BARS},
+};
+use color_eyre::{eyre::eyre, Report, Result};
+use midly::{
+    num::u28, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use std::{collections::BTreeMap, fs};
+
+// A tempo or meter change (or a marker) at an absolute position in MIDI ticks
+enum Change {
+    Tempo(u32), // microseconds per quarter note
+    Meter(u64, u64),
+    Marker(String),
+}
+
+// The bars one kind of import warning happened in, so it is reported once however many bars it happened in
+#[derive(Default)]
+struct WarningBars {
+    count: u64,
+    first: u64,
+    last: u64,
+}
+
+impl WarningBars {
+    fn add(&mut self, bar: u64) {
+        if self.count == 0 {
+            self.first = bar;
+        }
+        self.last = bar;
+        self.count += 1;
+    }
+
+    // ie "bar 3" or "bars 3-200"
+    fn bars(&self) -> String {
+        match self.first == self.last {
+            true => format!("bar {}", self.first),
+            false => format!("bars {}-{}", self.first, self.last),
+        }
+    }
+}
+
+// The result of importing a MIDI file, warnings are shown to the user in the notification area
+pub struct MidiImport {
+    pub song_map: SongMap,
+    pub warnings: Vec<String>,
+}
+
+pub fn import_song_map(path: &str) -> Result<MidiImport, Report> {
+    let bytes = match fs::read(path) {
+        Ok(value) => value,
+        Err(error) => return Err(eyre!("Could not read MIDI file {}: {}", path, error)),
+    };
+    parse_song_map(&bytes)
+}
+
+pub fn parse_song_map(bytes: &[u8]) -> Result<MidiImport, Report> {
+    let smf = match Smf::parse(bytes) {
+        Ok(value) => value,
+        Err(error) => return Err(eyre!("Not a valid MIDI file: {}", error)),
+    };
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(value) if value.as_int() > 0 => value.as_int() as u64,
+        Timing::Metrical(_) => {
+            return Err(eyre!("MIDI file has 0 ticks per quarter note"));
+        }
+        Timing::Timecode(..) => {
+            return Err(eyre!("MIDI files using SMPTE timecode are not supported"));
+        }
+    };
+
+    // Gather every change from every track on one timeline, counting up anything we can't use
+    let mut changes: Vec<(u64, Change)> = Vec::new();
+    let mut ignored: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut end_tick = 0;
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(value)) => {
+                    changes.push((tick, Change::Tempo(value.as_int())));
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(note, value_pow, _, _)) => {
+                    // keep the meter within what the metronome can play (and make sure bars are never empty)
+                    let meter = ((note as u64).clamp(1, 64), 1 << value_pow.min(6));
+                    changes.push((tick, Change::Meter(meter.0, meter.1)));
+                }
+                TrackEventKind::Meta(MetaMessage::Marker(text)) => {
+                    let name = String::from_utf8_lossy(text).trim().replace('"', "'");
+                    changes.push((tick, Change::Marker(name)));
+                }
+                TrackEventKind::Meta(MetaMessage::EndOfTrack)
+                | TrackEventKind::Meta(MetaMessage::TrackName(_)) => {}
+                TrackEventKind::Midi { .. } => {
+                    *ignored.entry("note / controller").or_default() += 1
+                }
+                TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => {
+                    *ignored.entry("sysex").or_default() += 1
+                }
+                TrackEventKind::Meta(_) => *ignored.entry("other meta").or_default() += 1,
+            }
+        }
+        end_tick = end_tick.max(tick);
+    }
+    // stable sort keeps events at the same tick in file order
+    changes.sort_by_key(|(tick, _)| *tick);
+
+    let mut warnings = Vec::new();
+    if !ignored.is_empty() {
+        let counts: Vec<String> = ignored
+            .iter()
+            .map(|(kind, count)| count.to_string() + " " + kind)
+            .collect();
+        warnings.push("ignored ".to_owned() + &counts.join(", ") + " events");
+    }
+
+    // Walk the file bar by bar. Changes take effect at the start of a bar, anything landing part way through a bar is
+    // moved to the start of the next one since the metronome can only switch on the bar boundary
+    let mut sections: Vec<Section> = Vec::new();
+    let mut quarter_us: u32 = 500_000; // MIDI defaults to 120 bpm 4/4
+    let (mut ts_note, mut ts_value) = (4, 4);
+    let mut marker: Option<String> = None;
+    let mut next_change = 0;
+    let mut bar_start = 0;
+    let mut bar = 1;
+    let mut moved_changes = WarningBars::default();
+    let mut tempo_out_of_range = WarningBars::default();
+    while bar_start < end_tick || sections.is_empty() {
+        while next_change < changes.len() && changes[next_change].0 <= bar_start {
+            let (tick, change) = &changes[next_change];
+            if *tick < bar_start {
+                moved_changes.add(bar - 1);
+            }
+            match change {
+                Change::Tempo(value) => quarter_us = *value,
+                Change::Meter(note, value) => (ts_note, ts_value) = (*note, *value),
+                Change::Marker(name) => marker = Some(name.clone()),
+            }
+            next_change += 1;
+        }
+
+        let bpm = app_bpm(quarter_us, ts_value);
+        if !(20.0..=500.0).contains(&bpm) {
+            tempo_out_of_range.add(bar);
+        }
+        let section = Section {
+            name: String::new(),
+            bars: 1,
+            bpm: bpm.clamp(20.0, 500.0),
+            ts_note,
+            ts_value,
+        };
+        // a marker always starts a new section, otherwise identical bars are merged into the current one until it is as
+        // long as a section can be
+        match (marker.take(), sections.last_mut()) {
+            (None, Some(last))
+                if (last.bpm, last.ts_note, last.ts_value)
+                    == (section.bpm, section.ts_note, section.ts_value)
+                    && last.bars < MAX_SECTION_BARS =>
+            {
+                last.bars += 1
+            }
+            (name, _) => sections.push(Section {
+                name: name
+                    .unwrap_or_else(|| "Section ".to_owned() + &(sections.len() + 1).to_string()),
+                ..section
+            }),
+        }
+
+        // a coarse enough file can round a bar (ie 1/64 at under 16 ticks a quarter) down to nothing
+        bar_start += (ticks_per_quarter * 4 * ts_note / ts_value).max(1);
+        bar += 1;
+    }
+    if moved_changes.count > 0 {
+        warnings.push(format!(
+            "moved {} change(s) inside {} to the start of the next bar",
+            moved_changes.count,
+            moved_changes.bars()
+        ));
+    }
+    if tempo_out_of_range.count > 0 {
+        warnings.push(format!(
+            "tempo out of range in {} ({} bars), clamped to 20 - 500",
+            tempo_out_of_range.bars(),
+            tempo_out_of_range.count
+        ));
+    }
+
+    Ok(MidiImport {
+        song_map: SongMap { sections },
+        warnings,
+    })
+}
+
+// Export resolution, divisible by 3 so that triplet clicks land exactly on a tick
+const EXPORT_TICKS_PER_QUARTER: u64 = 960;
+// General MIDI percussion notes (channel 10) and velocities for each kind of click
+const ACCENT_NOTE: (u8, u8) = (76, 127); // hi wood block
+const NORMAL_NOTE: (u8, u8) = (77, 100); // low wood block
+const SUBDIVISION_NOTE: (u8, u8) = (42, 70); // closed hi-hat
+
+// Write a click track out as a single track MIDI file with a note for every click, and tempo, time signature and
+// marker meta events wherever they change
+pub fn export_click_track(click_track: &[Bar], path: &str) -> Result<(), Report> {
+    // events are gathered with their absolute tick and sorted so meta events come first, then note offs, then note ons
+    let mut events: Vec<(u64, u8, TrackEventKind)> = vec![(
+        0,
+        0,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Ready Metronome click")),
+    )];
+    let mut bar_start = 0;
+    let mut last_meter = None;
+    let mut last_tempo = None;
+    for bar in click_track {
+        let quarters_per_bar = 4 * bar.ts_note;
+        let bar_ticks = EXPORT_TICKS_PER_QUARTER * quarters_per_bar / bar.ts_value;
+        // the tempo is worked out from how long the bar actually takes to play
+        let tempo =
+            (bar.length_ns() as f64 * bar.ts_value as f64 / quarters_per_bar as f64 / 1000.0)
+                .round() as u32;
+
+        if let Some(name) = bar.section_name.as_deref().filter(|name| !name.is_empty()) {
+            events.push((
+                bar_start,
+                0,
+                TrackEventKind::Meta(MetaMessage::Marker(name.as_bytes())),
+            ));
+        }
+        if last_meter != Some((bar.ts_note, bar.ts_value)) {
+            let value_pow = bar.ts_value.trailing_zeros() as u8;
+            let meter = MetaMessage::TimeSignature(bar.ts_note as u8, value_pow, 24, 8);
+            events.push((bar_start, 0, TrackEventKind::Meta(meter)));
+            last_meter = Some((bar.ts_note, bar.ts_value));
+        }
+        if last_tempo != Some(tempo) {
+            events.push((
+                bar_start,
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo.into())),
+            ));
+            last_tempo = Some(tempo);
+        }
+
+        // clicks are evenly spaced through the bar, each note lasts half a click (at most a 32nd note)
+        let num_clicks = bar.clicks.len() as u64;
+        let note_ticks = (bar_ticks / num_clicks / 2).min(EXPORT_TICKS_PER_QUARTER / 8);
+        for (index, kind) in bar.clicks.iter().enumerate() {
+            let tick = bar_start + index as u64 * bar_ticks / num_clicks;
+            let (key, vel) = match kind {
+                TickKind::Accent => ACCENT_NOTE,
+                TickKind::Normal => NORMAL_NOTE,
+                TickKind::Subdivision => SUBDIVISION_NOTE,
+            };
+            let note_on = MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            };
+            let note_off = MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 0.into(),
+            };
+            for (tick, order, message) in [(tick, 2, note_on), (tick + note_ticks, 1, note_off)] {
+                let channel = 9.into();
+                events.push((tick, order, TrackEventKind::Midi { channel, message }));
+            }
+        }
+        bar_start += bar_ticks;
+    }
+    events.push((bar_start, 3, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    events.sort_by_key(|(tick, order, _)| (*tick, *order));
+
+    let mut track = Vec::new();
+    let mut last_tick = 0;
+    for (tick, _, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new((tick - last_tick) as u32),
+            kind,
+        });
+        last_tick = tick;
+    }
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical((EXPORT_TICKS_PER_QUARTER as u16).into()),
+    ));
+    smf.tracks.push(track);
+    match smf.save(path) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(eyre!("Could not write MIDI file {}: {}", path, error)),
+    }
+}
+
+// Convert a MIDI tempo into the bpm the metronome uses for a time signature, so that one click lands on each of the
+// time signature's notes (ie eighth note meters count dotted quarters, see metronome::note_value_divisor). The tempo
+// is kept exactly so the click doesn't drift from the arrangement, but snaps to a whole bpm when that is what the file
+// was written at, as MIDI tempos are whole microseconds (ie 90 bpm is stored as 666,667)
+fn app_bpm(quarter_us: u32, ts_value: u64) -> f64 {
+    let beat_us = 4.0 / ts_value as f64 * note_value_divisor(ts_value);
+    let bpm = 60_000_000.0 / (quarter_us as f64 * beat_us);
+    let whole_bpm = bpm.round();
+    match (60_000_000.0 / (whole_bpm * beat_us)).round() as u32 == quarter_us {
+        true => whole_bpm,
+        false => bpm,
+    }
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::App,
+        click_track,
+        metronome::{InitMetronomeSettings, TickKind},
+    };
+    use std::sync::atomic::Ordering;
+
+    const TEST_SETTINGS: InitMetronomeSettings = InitMetronomeSettings {
+        bpm: 120,
+        ts_note: 4,
+        ts_value: 4,
+        volume: 100.0,
+        is_running: false,
+        debug: false,
+        count_in: false,
+        ts_triplets: false,
+        sub_eights: false,
+        sub_sixteens: false,
+        synth_pitch: 0.0,
+        synth_decay: 40.0,
+    };
+
+    fn meta(delta: u32, message: MetaMessage) -> TrackEvent {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(message),
+        }
+    }
+
+    // 8 bars of 4/4 at 120 then 4 bars of 3/4 at 90 with a note in the second track
+    fn test_file() -> Vec<u8> {
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Marker(b"Verse")),
+            meta(0, MetaMessage::Tempo(500_000.into())),
+            meta(0, MetaMessage::TimeSignature(4, 2, 24, 8)),
+            meta(480 * 4 * 8, MetaMessage::Tempo(666_667.into())),
+            meta(0, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            meta(480 * 3 * 4, MetaMessage::EndOfTrack),
+        ]);
+        smf.tracks.push(vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Midi {
+                channel: 9.into(),
+                message: MidiMessage::NoteOn {
+                    key: 37.into(),
+                    vel: 100.into(),
+                },
+            },
+        }]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn midi_parse_song_map() {
+        let import = parse_song_map(&test_file()).unwrap();
+        let sections = &import.song_map.sections;
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].to_line(), "8 x 4/4 @120 \"Verse\"");
+        assert_eq!(sections[1].to_line(), "4 x 3/4 @90 \"Section 2\"");
+        assert_eq!(import.warnings, vec!["ignored 1 note / controller events"]);
+    }
+
+    // eighth note meters count dotted quarters, so 140 bpm in quarters is 93.33 in 7/8
+    #[test]
+    fn midi_app_bpm() {
+        assert_eq!(app_bpm(500_000, 4), 120.0);
+        assert_eq!(app_bpm(666_667, 4), 90.0);
+        assert_eq!(app_bpm(428_571, 4), 140.0);
+        assert!((app_bpm(428_571, 8) - 93.3334).abs() < 0.0001);
+        assert_eq!(app_bpm(500_000, 16), 120.0);
+    }
+
+    // a fractional tempo should play exactly as long as the file says, over a whole song
+    #[test]
+    fn midi_exact_tempo() {
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(480.into()),
+        ));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(428_571.into())),
+            meta(0, MetaMessage::TimeSignature(7, 3, 24, 8)),
+            meta(240 * 7 * 100, MetaMessage::EndOfTrack),
+        ]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let section = parse_song_map(&bytes).unwrap().song_map.sections[0].clone();
+        assert_eq!(section.bars, 100);
+
+        // the eighth note is half of 428,571 microseconds, even after saving the song map as text and loading it back
+        let section = crate::song_map::parse_section(&section.to_line()).unwrap();
+        let app = App::new(TEST_SETTINGS, 7);
+        app.settings
+            .set_tempo_and_meter(section.bpm, section.ts_note, section.ts_value);
+        let ns_delay = app
+            .settings
+            .ns_delay
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert!(ns_delay.abs_diff(214_285_500) <= 1);
+    }
+
+    // a file without any ticks per quarter note can't be laid out in bars
+    #[test]
+    fn midi_zero_ticks_per_quarter() {
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(0.into())));
+        smf.tracks.push(vec![meta(10, MetaMessage::EndOfTrack)]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        assert!(parse_song_map(&bytes).is_err());
+    }
+
+    // a bar of 1/64 at 8 ticks a quarter note is half a tick, it should still move the import along
+    #[test]
+    fn midi_bar_shorter_than_a_tick() {
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(8.into())));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::TimeSignature(1, 6, 24, 8)),
+            meta(4, MetaMessage::EndOfTrack),
+        ]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let sections = parse_song_map(&bytes).unwrap().song_map.sections;
+        assert_eq!(sections[0].bars, 4);
+        assert_eq!(sections[0].ts_value, 64);
+    }
+
+    // problems that repeat bar after bar are reported once each, with the bars they happened in
+    #[test]
+    fn midi_grouped_warnings() {
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(480.into()),
+        ));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(50_000.into())),
+            meta(1_920 * 2 + 10, MetaMessage::Marker(b"A")),
+            meta(1_920, MetaMessage::Marker(b"B")),
+            meta(1_920 * 197 - 30, MetaMessage::EndOfTrack),
+        ]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let import = parse_song_map(&bytes).unwrap();
+        assert_eq!(
+            import.warnings,
+            [
+                "moved 2 change(s) inside bars 3-4 to the start of the next bar",
+                "tempo out of range in bars 1-200 (200 bars), clamped to 20 - 500",
+            ]
+        );
+    }
+
+    // a song too long for one section is split so every section still loads from the saved song map
+    #[test]
+    fn midi_long_section() {
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(1.into())));
+        smf.tracks.push(vec![meta(
+            4 * (MAX_SECTION_BARS as u32 + 2),
+            MetaMessage::EndOfTrack,
+        )]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let sections = parse_song_map(&bytes).unwrap().song_map.sections;
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].bars, MAX_SECTION_BARS);
+        assert_eq!(sections[1].bars, 2);
+        assert!(crate::song_map::parse_section(&sections[0].to_line()).is_ok());
+    }
+
+    // exporting a click track and importing it again should give back the same tempo and meter
+    #[test]
+    fn midi_export_round_trip() {
+        let init_settings = InitMetronomeSettings {
+            sub_eights: true,
+            ..TEST_SETTINGS
+        };
+        let app = App::new(init_settings, 7);
+        app.settings.set_tempo_and_meter(100.0, 5, 4);
+        *app.settings.song_map.write().unwrap() =
+            Some(parse_song_map(&test_file()).unwrap().song_map);
+        let click_track = click_track::build(&app.settings, None);
+        assert_eq!(click_track.len(), 12);
+        assert_eq!(
+            click_track[0].clicks[..3],
+            [TickKind::Accent, TickKind::Subdivision, TickKind::Normal]
+        );
+        assert_eq!(click_track[0].ns_delay, 250_000_000);
+        assert_eq!(click_track[8].clicks.len(), 6);
+
+        // laying out the song shouldn't move the tempo that is playing
+        let settings = &app.settings;
+        assert_eq!(settings.bpm.load(Ordering::Relaxed), 100);
+        assert_eq!(settings.ts_note.load(Ordering::Relaxed), 5);
+        assert_eq!(settings.ts_value.load(Ordering::Relaxed), 4);
+        assert_eq!(settings.beats_per_bar.load(Ordering::Relaxed), 10);
+        assert_eq!(settings.ns_delay.load(Ordering::Relaxed), 300_000_000);
+
+        let path = std::env::temp_dir().join("readymetronome_export_test.mid");
+        export_click_track(&click_track, path.to_str().unwrap()).unwrap();
+        let import = import_song_map(path.to_str().unwrap()).unwrap();
+        let _ = fs::remove_file(path);
+        let lines: Vec<String> = import
+            .song_map
+            .sections
+            .iter()
+            .map(|s| s.to_line())
+            .collect();
+        assert_eq!(
+            lines,
+            ["8 x 4/4 @120 \"Verse\"", "4 x 3/4 @90 \"Section 2\""]
+        );
+        assert_eq!(import.warnings, ["ignored 176 note / controller events"]);
+    }
+
+    #[test]
+    fn midi_parse_not_midi() {
+        assert!(parse_song_map(b"8 x 4/4 @120").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+/// Processing.rs cleans up click samples when they are loaded. Samples can have their leading silence trimmed (which
+/// would otherwise make the click sound late), be normalized so different samples play at a similar loudness and be
+/// pitch shifted. Each sound has its own settings, which are saved in the config file.
+use rodio::{buffer::SamplesBuffer, Source};
+use serde::{Deserialize, Serialize};
+
+use crate::sounds::SoundSource;
+
+// Anything quieter than this at the start of a sample counts as silence
+const TRIM_THRESHOLD_DB: f32 = -50.0;
+// Peak normalizing leaves a little headroom, RMS normalizing aims for a typical click loudness
+const PEAK_TARGET_DB: f32 = -1.0;
+const RMS_TARGET_DB: f32 = -18.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalize {
+    #[default]
+    Off,
+    Peak,
+    Rms,
+}
+
+impl Normalize {
+    pub fn next(&self) -> Normalize {
+        match self {
+            Normalize::Off => Normalize::Peak,
+            Normalize::Peak => Normalize::Rms,
+            Normalize::Rms => Normalize::Off,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Normalize::Off => "off",
+            Normalize::Peak => "peak",
+            Normalize::Rms => "rms",
+        }
+    }
+}
+
+// The processing settings for one sound. pitch is in semitones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundProcessing {
+    pub trim: bool,
+    pub normalize: Normalize,
+    pub pitch: f64,
+}
+
+// A fully decoded sample, kept in memory so it doesn't have to be read and processed again on every click
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedSample {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>, // interleaved
+}
+
+impl LoadedSample {
+    // Decode the whole of a sound into memory
+    pub fn from_source(source: SoundSource) -> LoadedSample {
+        LoadedSample {
+            channels: source.channels(),
+            sample_rate: source.sample_rate(),
+            samples: source.collect(),
+        }
+    }
+
+    pub fn process(mut self, processing: SoundProcessing) -> LoadedSample {
+        if processing.trim {
+            self.trim_silence();
+        }
+        if processing.pitch != 0.0 {
+            self.pitch_shift(processing.pitch);
+        }
+        match processing.normalize {
+            Normalize::Off => {}
+            Normalize::Peak => self.normalize_peak(),
+            Normalize::Rms => self.normalize_rms(),
+        }
+        self
+    }
+
+    pub fn to_source(&self) -> SoundSource {
+        Box::new(SamplesBuffer::new(
+            self.channels,
+            self.sample_rate,
+            self.samples.clone(),
+        ))
+    }
+
+    // Drop every frame before the first one that is louder than the threshold on any channel
+    fn trim_silence(&mut self) {
+        let threshold = db_to_gain(TRIM_THRESHOLD_DB);
+        let channels = self.channels.max(1) as usize;
+        let first_sound = self
+            .samples
+            .iter()
+            .position(|value| value.abs() > threshold)
+            .map(|index| index / channels * channels)
+            .unwrap_or(self.samples.len());
+        self.samples.drain(..first_sound);
+    }
+
+    // Resample so the sample plays back higher or lower (and shorter or longer) like a sampler would
+    fn pitch_shift(&mut self, semitones: f64) {
+        let ratio = 2_f64.powf(semitones / 12.0);
+        let channels = self.channels.max(1) as usize;
+        let frames = self.samples.len() / channels;
+        let new_frames = (frames as f64 / ratio) as usize;
+        let mut shifted = Vec::with_capacity(new_frames * channels);
+        for frame in 0..new_frames {
+            let position = frame as f64 * ratio;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            for channel in 0..channels {
+                let current = self.samples[index * channels + channel];
+                let next = match index + 1 < frames {
+                    true => self.samples[(index + 1) * channels + channel],
+                    false => 0.0,
+                };
+                shifted.push(current + (next - current) * fraction);
+            }
+        }
+        self.samples = shifted;
+    }
+
+    fn normalize_peak(&mut self) {
+        let peak = self.peak();
+        if peak > 0.0 {
+            self.apply_gain(db_to_gain(PEAK_TARGET_DB) / peak);
+        }
+    }
+
+    // Match the average loudness, without letting the peaks clip
+    fn normalize_rms(&mut self) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let rms = (self.samples.iter().map(|value| value * value).sum::<f32>()
+            / self.samples.len() as f32)
+            .sqrt();
+        let peak = self.peak();
+        if rms <= 0.0 {
+            return;
+        }
+        self.apply_gain((db_to_gain(RMS_TARGET_DB) / rms).min(1.0 / peak));
+    }
+
+    fn peak(&self) -> f32 {
+        self.samples
+            .iter()
+            .fold(0.0_f32, |peak, value| peak.max(value.abs()))
+    }
+
+    fn apply_gain(&mut self, gain: f32) {
+        for value in self.samples.iter_mut() {
+            *value *= gain;
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10_f32.powf(db / 20.0)
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_sample(samples: Vec<f32>) -> LoadedSample {
+        LoadedSample {
+            channels: 2,
+            sample_rate: 44_100,
+            samples,
+        }
+    }
+
+    // leading silence should be trimmed a whole frame at a time
+    #[test]
+    fn processing_trim() {
+        let processing = SoundProcessing {
+            trim: true,
+            ..Default::default()
+        };
+        let sample =
+            test_sample(vec![0.0, 0.0, 0.0001, 0.0, 0.0, 0.5, 0.2, 0.0]).process(processing);
+        assert_eq!(sample.samples, vec![0.0, 0.5, 0.2, 0.0]);
+    }
+
+    #[test]
+    fn processing_normalize() {
+        let peak = SoundProcessing {
+            normalize: Normalize::Peak,
+            ..Default::default()
+        };
+        let sample = test_sample(vec![0.25, -0.5, 0.1, 0.0]).process(peak);
+        assert!((sample.samples[1] + db_to_gain(PEAK_TARGET_DB)).abs() < 0.0001);
+
+        // a quiet sample is brought up to the RMS target, a loud spiky one is limited by its peak
+        let rms = SoundProcessing {
+            normalize: Normalize::Rms,
+            ..Default::default()
+        };
+        let sample = test_sample(vec![0.01, -0.01, 0.01, -0.01]).process(rms);
+        assert!((sample.samples[0] - db_to_gain(RMS_TARGET_DB)).abs() < 0.0001);
+        let sample = test_sample(vec![0.5, 0.0, 0.0, 0.0]).process(rms);
+        assert!(sample.samples[0] <= 1.0);
+    }
+
+    // up an octave should play twice as fast
+    #[test]
+    fn processing_pitch() {
+        let processing = SoundProcessing {
+            pitch: 12.0,
+            ..Default::default()
+        };
+        let sample = test_sample(vec![0.0, 1.0, 0.2, 0.8, 0.4, 0.6, 0.6, 0.4]).process(processing);
+        assert_eq!(sample.samples, vec![0.0, 1.0, 0.4, 0.6]);
+    }
+}
@@ -0,0 +1,142 @@
+/// Render.rs renders the click track to a WAV file offline. The selected sound is mixed in at the exact position of
+/// every click without opening an audio device, at the same gain as live playback.
+// hound docs: https://docs.rs/hound/latest/hound/
+use crate::{
+    click_track::Bar,
+    metronome::{tick_gain, TickKind},
+    sounds::SoundSource,
+};
+use color_eyre::{eyre::eyre, Report, Result};
+use rodio::source::UniformSourceIterator;
+
+const RENDER_SAMPLE_RATE: u32 = 44_100;
+const RENDER_CHANNELS: u16 = 2;
+
+// The decoded sample for each kind of click, sound packs can use a different file for each
+struct Samples {
+    accent: Vec<f32>,
+    normal: Vec<f32>,
+    subdivision: Vec<f32>,
+}
+
+impl Samples {
+    fn get(&self, kind: TickKind) -> &[f32] {
+        match kind {
+            TickKind::Accent => &self.accent,
+            TickKind::Normal => &self.normal,
+            TickKind::Subdivision => &self.subdivision,
+        }
+    }
+}
+
+pub fn render_wav(
+    click_track: &[Bar],
+    open_sound: impl Fn(TickKind) -> Result<SoundSource, Report>,
+    volume: f64,
+    path: &str,
+) -> Result<(), Report> {
+    // decode each sound once, converted to the output format the same way rodio's mixer does during playback
+    let decode = |kind| -> Result<Vec<f32>, Report> {
+        Ok(
+            UniformSourceIterator::new(open_sound(kind)?, RENDER_CHANNELS, RENDER_SAMPLE_RATE)
+                .collect(),
+        )
+    };
+    let samples = Samples {
+        accent: decode(TickKind::Accent)?,
+        normal: decode(TickKind::Normal)?,
+        subdivision: decode(TickKind::Subdivision)?,
+    };
+    let mix = mix_click_track(click_track, &samples, volume);
+
+    let spec = hound::WavSpec {
+        channels: RENDER_CHANNELS,
+        sample_rate: RENDER_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let write = || -> Result<(), hound::Error> {
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for value in &mix {
+            writer.write_sample((value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()
+    };
+    match write() {
+        Ok(_) => Ok(()),
+        Err(error) => Err(eyre!("Could not write WAV file {}: {}", path, error)),
+    }
+}
+
+// Mix a copy of the sample into an interleaved buffer at every click, the buffer lasts until the end of the last bar
+// (or the end of the last click if it rings on past that)
+fn mix_click_track(click_track: &[Bar], samples: &Samples, volume: f64) -> Vec<f32> {
+    let gain = tick_gain(volume);
+    let mut mix: Vec<f32> = Vec::new();
+    let mut bar_start_ns = 0;
+    for bar in click_track {
+        for (index, kind) in bar.clicks.iter().enumerate() {
+            let start = sample_index(bar_start_ns + index as u64 * bar.ns_delay);
+            let sample = samples.get(*kind);
+            if mix.len() < start + sample.len() {
+                mix.resize(start + sample.len(), 0.0);
+            }
+            for (mixed, value) in mix[start..].iter_mut().zip(sample) {
+                *mixed += value * gain;
+            }
+        }
+        bar_start_ns += bar.length_ns();
+    }
+    let end = sample_index(bar_start_ns);
+    if mix.len() < end {
+        mix.resize(end, 0.0);
+    }
+    mix
+}
+
+// Position in the interleaved output buffer of the first sample of the frame playing at a time
+fn sample_index(time_ns: u64) -> usize {
+    let frame = (time_ns as u128 * RENDER_SAMPLE_RATE as u128 + 500_000_000) / 1_000_000_000;
+    frame as usize * RENDER_CHANNELS as usize
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metronome::TickKind;
+
+    // one bar of 4/4 at 120 with eighths, as the click track would lay it out
+    fn test_bar() -> Bar {
+        Bar {
+            section_name: None,
+            ts_note: 4,
+            ts_value: 4,
+            ns_delay: 250_000_000,
+            clicks: (0..8)
+                .map(|tick| match tick {
+                    0 => TickKind::Accent,
+                    tick if tick % 2 == 0 => TickKind::Normal,
+                    _ => TickKind::Subdivision,
+                })
+                .collect(),
+        }
+    }
+
+    // every click should land on the exact frame it is scheduled for with its own kind's sample, at the same gain as
+    // live playback
+    #[test]
+    fn render_mix_click_track() {
+        let samples = Samples {
+            accent: vec![0.8, 0.8],
+            normal: vec![0.4, 0.4],
+            subdivision: vec![0.2, 0.2],
+        };
+        let mix = mix_click_track(&[test_bar()], &samples, 50.0);
+        assert_eq!(mix.len(), sample_index(2_000_000_000));
+        assert_eq!(mix[0], 0.4);
+        assert_eq!(mix[sample_index(250_000_000)], 0.1);
+        assert_eq!(mix[sample_index(500_000_000) + 1], 0.2);
+        assert_eq!(mix[2], 0.0);
+    }
+}
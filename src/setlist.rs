@@ -0,0 +1,180 @@
+/// Setlist.rs holds presets and setlists. A preset is a saved tempo and time signature for a song, a setlist is an
+/// ordered list of presets that can be stepped through with the next / previous keys while playing.
+// Setlist files are plain text with one song per line in the form `4/4 @120 "Song Title"`. Blank lines and lines
+// starting with '#' are ignored.
+use color_eyre::{eyre::eyre, Report, Result};
+use std::fs;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub bpm: u64,
+    pub ts_note: u64,
+    pub ts_value: u64,
+}
+
+#[derive(Debug)]
+pub struct Setlist {
+    pub presets: Vec<Preset>,
+    pub position: usize,
+}
+
+impl Setlist {
+    pub fn new(presets: Vec<Preset>) -> Setlist {
+        Setlist {
+            presets,
+            position: 0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Setlist, Report> {
+        let contents = match fs::read_to_string(path) {
+            Ok(value) => value,
+            Err(error) => return Err(eyre!("Could not read setlist {}: {}", path, error)),
+        };
+        Setlist::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Setlist, Report> {
+        let mut presets = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_preset(line) {
+                Ok(preset) => presets.push(preset),
+                Err(error) => return Err(eyre!("Setlist line {}: {}", index + 1, error)),
+            }
+        }
+        if presets.is_empty() {
+            return Err(eyre!("Setlist does not contain any songs"));
+        }
+        Ok(Setlist::new(presets))
+    }
+
+    pub fn current(&self) -> &Preset {
+        &self.presets[self.position]
+    }
+
+    // Step to the next song, returns None if we are already at the end of the set
+    pub fn next(&mut self) -> Option<&Preset> {
+        if self.position + 1 >= self.presets.len() {
+            return None;
+        }
+        self.position += 1;
+        Some(self.current())
+    }
+
+    // Step to the previous song, returns None if we are already at the start of the set
+    pub fn previous(&mut self) -> Option<&Preset> {
+        if self.position == 0 {
+            return None;
+        }
+        self.position -= 1;
+        Some(self.current())
+    }
+
+    // Position in the set for display, ie "3/12"
+    pub fn position_string(&self) -> String {
+        (self.position + 1).to_string() + "/" + &self.presets.len().to_string()
+    }
+}
+
+// Parse a single `4/4 @120 "Song Title"` line into a preset
+fn parse_preset(line: &str) -> Result<Preset, String> {
+    let (settings, name) = match line.find('"') {
+        Some(start) => {
+            let rest = &line[start + 1..];
+            match rest.find('"') {
+                Some(end) => (&line[..start], rest[..end].to_string()),
+                None => return Err("song title is missing its closing quote".to_string()),
+            }
+        }
+        None => return Err("expected a song title in quotes".to_string()),
+    };
+
+    let mut bpm = None;
+    let mut time_signature = None;
+    for token in settings.split_whitespace() {
+        if let Some(value) = token.strip_prefix('@') {
+            bpm = Some(parse_bpm(value)?);
+        } else if token.contains('/') {
+            time_signature = Some(parse_time_signature(token)?);
+        } else {
+            return Err(format!("unexpected '{}'", token));
+        }
+    }
+
+    let bpm = bpm.ok_or("expected a tempo like @120")?;
+    let (ts_note, ts_value) = time_signature.ok_or("expected a time signature like 4/4")?;
+    Ok(Preset {
+        name,
+        bpm,
+        ts_note,
+        ts_value,
+    })
+}
+
+pub fn parse_bpm(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(bpm) if (20..=500).contains(&bpm) => Ok(bpm),
+        _ => Err(format!("'{}' is not a tempo between 20 and 500", value)),
+    }
+}
+
+pub fn parse_time_signature(value: &str) -> Result<(u64, u64), String> {
+    let error = || format!("'{}' is not a valid time signature", value);
+    let (note, note_value) = value.split_once('/').ok_or_else(error)?;
+    let note: u64 = note.parse().map_err(|_| error())?;
+    let note_value: u64 = note_value.parse().map_err(|_| error())?;
+    if !(1..=64).contains(&note) || ![1, 2, 4, 8, 16, 32, 64].contains(&note_value) {
+        return Err(error());
+    }
+    Ok((note, note_value))
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SETLIST: &str = "# opener\n4/4 @120 \"First Song\"\n\n@96 6/8 \"Second Song\"\n";
+
+    #[test]
+    fn setlist_parse() {
+        let setlist = Setlist::parse(TEST_SETLIST).unwrap();
+        assert_eq!(setlist.presets.len(), 2);
+        assert_eq!(
+            setlist.presets[1],
+            Preset {
+                name: "Second Song".to_string(),
+                bpm: 96,
+                ts_note: 6,
+                ts_value: 8,
+            }
+        );
+    }
+
+    #[test]
+    fn setlist_parse_bad_line() {
+        let error = Setlist::parse("4/4 @120 \"Fine\"\n4/4 @9000 \"Too fast\"").unwrap_err();
+        assert!(error.to_string().starts_with("Setlist line 2"));
+    }
+
+    #[test]
+    fn setlist_parse_empty() {
+        assert!(Setlist::parse("# nothing to see here\n").is_err());
+    }
+
+    // next and previous should stop at either end of the set
+    #[test]
+    fn setlist_step() {
+        let mut setlist = Setlist::parse(TEST_SETLIST).unwrap();
+        assert_eq!(setlist.position_string(), "1/2");
+        assert!(setlist.previous().is_none());
+        assert_eq!(setlist.next().unwrap().name, "Second Song");
+        assert!(setlist.next().is_none());
+        assert_eq!(setlist.position_string(), "2/2");
+    }
+}
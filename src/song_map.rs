@@ -0,0 +1,316 @@
+/// Song_map.rs holds the song structure (tempo map) of a tune. A song map is a list of sections, each with a bar count,
+/// time signature and tempo. The metronome thread walks through it using bar_count and switches tempo and meter
+/// exactly on the bar boundary.
+// Song maps are saved as plain text with one section per line in the form `8 x 4/4 @120 "Verse"`. Blank lines and
+// lines starting with '#' are ignored.
+use crate::setlist::parse_time_signature;
+use color_eyre::{eyre::eyre, Report, Result};
+use std::fs;
+
+// The most bars a section can have, far longer than any song but small enough that adding up sections can't overflow
+pub const MAX_SECTION_BARS: u64 = 9_999;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub bars: u64,
+    pub bpm: f64, // fractional when imported from MIDI so the click doesn't drift from the arrangement
+    pub ts_note: u64,
+    pub ts_value: u64,
+}
+
+impl Section {
+    // A single line of the song map file, ie `8 x 4/4 @120 "Verse"`. Names can't hold a '"' as it would end the name
+    // early, so any that got in are written as a '
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} x {}/{} @{} \"{}\"",
+            self.bars,
+            self.ts_note,
+            self.ts_value,
+            format_bpm(self.bpm),
+            self.name.replace('"', "'")
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SongMap {
+    pub sections: Vec<Section>,
+}
+
+impl SongMap {
+    pub fn load(path: &str) -> Result<SongMap, Report> {
+        match fs::read_to_string(path) {
+            Ok(contents) => SongMap::parse(&contents).map_err(|error| eyre!("{}: {}", path, error)),
+            Err(error) => Err(eyre!("Could not read song map {}: {}", path, error)),
+        }
+    }
+
+    // An empty song map would not load back, so it isn't saved
+    pub fn save(&self, path: &str) -> Result<(), Report> {
+        if self.sections.is_empty() {
+            return Err(eyre!("Add a section before saving the song map"));
+        }
+        match fs::write(path, self.to_text()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Could not save song map {}: {}", path, error)),
+        }
+    }
+
+    // Parse a whole song map file, errors point at the line and column of the problem
+    pub fn parse(contents: &str) -> Result<SongMap, Report> {
+        let mut sections = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match parse_section(line) {
+                Ok(section) => sections.push(section),
+                Err((column, message)) => {
+                    return Err(eyre!("line {}, column {}: {}", index + 1, column, message))
+                }
+            }
+        }
+        if sections.is_empty() {
+            return Err(eyre!("song map does not contain any sections"));
+        }
+        Ok(SongMap { sections })
+    }
+
+    // Write the song map back out in the same format it is parsed from
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for section in &self.sections {
+            text += &section.to_line();
+            text.push('\n');
+        }
+        text
+    }
+
+    // Find the section that a (1 based) bar falls into. The count-in bar (bar 0) uses the first section's settings.
+    // Returns None once the song is over
+    pub fn section_at_bar(&self, bar: u64) -> Option<(usize, &Section)> {
+        let mut last_bar_of_section = 0;
+        for (index, section) in self.sections.iter().enumerate() {
+            last_bar_of_section = section.bars.saturating_add(last_bar_of_section);
+            if bar <= last_bar_of_section {
+                return Some((index, section));
+            }
+        }
+        None
+    }
+}
+
+// Parse a single `8 x 4/4 @120 "Verse"` line into a section. Errors return the (1 based) column of the problem
+pub fn parse_section(line: &str) -> Result<Section, (usize, String)> {
+    let mut tokens = tokenize(line)?.into_iter();
+    let end_column = line.chars().count() + 1;
+    let mut expect = |what: &str| {
+        tokens
+            .next()
+            .ok_or_else(|| (end_column, format!("expected {}", what)))
+    };
+
+    let (column, bars) = expect("a bar count")?;
+    let bars = match bars.parse::<u64>() {
+        Ok(value) if (1..=MAX_SECTION_BARS).contains(&value) => value,
+        _ => {
+            return Err((
+                column,
+                format!(
+                    "'{}' is not a bar count between 1 and {}",
+                    bars, MAX_SECTION_BARS
+                ),
+            ))
+        }
+    };
+    let (column, times) = expect("'x' after the bar count")?;
+    if times != "x" {
+        return Err((column, format!("expected 'x' but found '{}'", times)));
+    }
+    let (column, time_signature) = expect("a time signature like 4/4")?;
+    let (ts_note, ts_value) = parse_time_signature(&time_signature).map_err(|e| (column, e))?;
+    let (column, tempo) = expect("a tempo like @120")?;
+    let bpm = match tempo.strip_prefix('@') {
+        Some(value) => parse_section_bpm(value).map_err(|e| (column, e))?,
+        None => {
+            return Err((
+                column,
+                format!("expected a tempo like @120 but found '{}'", tempo),
+            ))
+        }
+    };
+
+    // The section name is optional but must be the last thing on the line
+    let mut name = String::new();
+    if let Ok((column, token)) = expect("a section name") {
+        match token.strip_prefix('"') {
+            Some(value) => name = value.to_string(),
+            None => return Err((column, "expected a section name in quotes".to_string())),
+        }
+    }
+    if let Ok((column, extra)) = expect("the end of the line") {
+        return Err((column, format!("unexpected '{}'", extra)));
+    }
+
+    Ok(Section {
+        name,
+        bars,
+        bpm,
+        ts_note,
+        ts_value,
+    })
+}
+
+// Song map tempos can have a fractional part, unlike setlist tempos
+fn parse_section_bpm(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(bpm) if (20.0..=500.0).contains(&bpm) => Ok(bpm),
+        _ => Err(format!("'{}' is not a tempo between 20 and 500", value)),
+    }
+}
+
+// Whole tempos are written as they are, fractional ones to a millionth of a bpm which is well within a microsecond
+// over a whole song
+fn format_bpm(bpm: f64) -> String {
+    let text = format!("{:.6}", bpm);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+// Split a line into whitespace separated tokens along with their (1 based) column. A quoted name is kept as a single
+// token that starts with '"' and has its closing quote removed
+fn tokenize(line: &str) -> Result<Vec<(usize, String)>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().enumerate().peekable();
+    while let Some((index, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut token = c.to_string();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    // a quote inside the name would read as the end of it
+                    Some((column, '"'))
+                        if chars.peek().is_some_and(|(_, c)| !c.is_whitespace()) =>
+                    {
+                        return Err((
+                            column + 1,
+                            "section names can't contain '\"', use ' instead".to_string(),
+                        ))
+                    }
+                    Some((_, '"')) => break,
+                    Some((_, c)) => token.push(c),
+                    None => {
+                        return Err((index + 1, "name is missing its closing quote".to_string()))
+                    }
+                }
+            }
+        } else {
+            while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push((index + 1, token));
+    }
+    Ok(tokens)
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_map() -> SongMap {
+        SongMap {
+            sections: vec![
+                Section {
+                    name: "Verse".to_string(),
+                    bars: 8,
+                    bpm: 120.0,
+                    ts_note: 4,
+                    ts_value: 4,
+                },
+                Section {
+                    name: "Bridge".to_string(),
+                    bars: 4,
+                    bpm: 140.0,
+                    ts_note: 7,
+                    ts_value: 8,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn song_map_parse() {
+        let map = SongMap::parse("# Tune\n8 x 4/4 @120 \"Verse\"\n\n  4 x 7/8 @140 \"Bridge\"\n")
+            .unwrap();
+        assert_eq!(map, test_map());
+    }
+
+    // parse errors should point at the line and column of the problem
+    #[test]
+    fn song_map_parse_errors() {
+        let error = SongMap::parse("8 x 4/4 @120 \"Verse\"\n4 x 7/8 @900 \"Bridge\"").unwrap_err();
+        assert!(error.to_string().starts_with("line 2, column 9:"));
+        assert_eq!(parse_section("8 x 4/4 @120 \"Verse").unwrap_err().0, 14);
+        assert_eq!(parse_section("8 by 4/4 @120").unwrap_err().0, 3);
+        assert_eq!(parse_section("8 x 4/4").unwrap_err().0, 8);
+        assert!(parse_section("8 x 4/4 @120").unwrap().name.is_empty());
+        // a quote inside the name is pointed at rather than cutting the name short
+        assert_eq!(
+            parse_section("8 x 4/4 @120 \"Say \"Hi\"\"").unwrap_err().0,
+            19
+        );
+    }
+
+    // a song map should come back unchanged after saving and loading it again
+    #[test]
+    fn song_map_round_trip() {
+        let map = test_map();
+        assert_eq!(SongMap::parse(&map.to_text()).unwrap(), map);
+
+        // quotes in a name (ie from a MIDI marker) are written so the line still loads
+        let mut quoted = test_map();
+        quoted.sections[0].name = "Say \"Hi\"".to_string();
+        let loaded = SongMap::parse(&quoted.to_text()).unwrap();
+        assert_eq!(loaded.sections[0].name, "Say 'Hi'");
+    }
+
+    // an empty song map can't be loaded back so it is never saved
+    #[test]
+    fn song_map_save_empty() {
+        let path = std::env::temp_dir().join("readymetronome_empty_song_map.txt");
+        let path = path.to_str().unwrap();
+        assert!(SongMap::default().save(path).is_err());
+        assert!(fs::metadata(path).is_err());
+    }
+
+    // song_map::section_at_bar should switch sections exactly on the bar boundary
+    #[test]
+    fn song_map_section_at_bar() {
+        let map = test_map();
+        assert_eq!(map.section_at_bar(0).unwrap().1.name, "Verse");
+        assert_eq!(map.section_at_bar(8).unwrap().1.name, "Verse");
+        assert_eq!(map.section_at_bar(9).unwrap().0, 1);
+        assert_eq!(map.section_at_bar(12).unwrap().1.name, "Bridge");
+        assert!(map.section_at_bar(13).is_none());
+    }
+
+    // huge bar counts are rejected when parsing, and can't overflow the bar count if a map holds them anyway
+    #[test]
+    fn song_map_huge_bar_count() {
+        let line = "18446744073709551615 x 4/4 @120";
+        assert_eq!(parse_section(line).unwrap_err().0, 1);
+        assert!(SongMap::parse(&(line.to_owned() + "\n" + line)).is_err());
+        assert!(parse_section("9999 x 4/4 @120").is_ok());
+        assert!(parse_section("10000 x 4/4 @120").is_err());
+
+        let mut map = test_map();
+        map.sections[0].bars = u64::MAX;
+        map.sections[1].bars = u64::MAX;
+        assert_eq!(map.section_at_bar(u64::MAX).unwrap().0, 0);
+    }
+}
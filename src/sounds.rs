@@ -0,0 +1,725 @@
+/// Sounds.rs holds the sounds the metronome can click with. A sound is either a file from one of the sound folders, a
+/// pack of files playing different roles in the bar, one of the default samples embedded in the binary or one of the
+/// built in synth voices. They are all opened as a rodio Source so the rest of the app can treat them the same.
+// Sound folders are searched in order: --assets-dir, $XDG_DATA_HOME/readymetronome/sounds and the assets folder next to
+// the executable. If two folders have a sound with the same name the first one found is used, and the embedded
+// samples are only listed when no folder has a file of the same name.
+//
+// Sub-folders of a sound folder are categories (ie "Woodblocks"). A category can have a pack.toml manifest giving it a
+// display name and assigning its files to the accent, normal and subdivision clicks, which makes it selectable as a
+// pack:
+//
+//     name = "Wood Blocks"
+//     accent = "high.wav"
+//     normal = "mid.wav"
+//     subdivision = "low.wav"
+//
+// Every key is optional. Missing roles fall back to the normal click, and a missing normal click to the accent.
+use crate::{
+    metronome::TickKind,
+    synth::{Synth, SynthVoice},
+};
+use color_eyre::{eyre::eyre, Report, Result};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rodio::{Decoder, Source};
+use serde::Deserialize;
+use std::{
+    env, fs,
+    fs::File,
+    io::{self, Cursor, Read, Seek},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+// Anything longer than this is more likely a song than a click, and would pile up when played on every beat
+pub const MAX_SOUND_LENGTH: Duration = Duration::from_secs(2);
+
+const PACK_MANIFEST: &str = "pack.toml";
+
+// How long the sound folders have to stay quiet after a change before they are searched again, copying a few files in
+// fires lots of events
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// A click sound ready to be mixed into the output, boxed so file and synth sounds can be swapped at runtime
+pub type SoundSource = Box<dyn Source<Item = f32> + Send>;
+
+// A sample compiled into the binary so the default sounds work wherever it is run from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmbeddedSound {
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+}
+
+pub const EMBEDDED_SOUNDS: [EmbeddedSound; 2] = [
+    EmbeddedSound {
+        name: "EmeryBoardClick.wav",
+        bytes: include_bytes!("../assets/EmeryBoardClick.wav"),
+    },
+    EmbeddedSound {
+        name: "TronicClick1.wav",
+        bytes: include_bytes!("../assets/TronicClick1.wav"),
+    },
+];
+
+// A category whose pack.toml assigns its files to the different clicks in the bar
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundPack {
+    pub category: String,
+    pub accent: PathBuf,
+    pub normal: PathBuf,
+    pub subdivision: PathBuf,
+}
+
+impl SoundPack {
+    pub fn role_path(&self, kind: TickKind) -> &Path {
+        match kind {
+            TickKind::Accent => &self.accent,
+            TickKind::Normal => &self.normal,
+            TickKind::Subdivision => &self.subdivision,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackManifest {
+    name: Option<String>,
+    accent: Option<String>,
+    normal: Option<String>,
+    subdivision: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sound {
+    File {
+        path: PathBuf,
+        category: Option<String>, // the category folder the file is in, None for the top of a sound folder
+    },
+    Pack(SoundPack),
+    Embedded(EmbeddedSound),
+    Synth(SynthVoice),
+}
+
+impl Sound {
+    // A file at the top of a sound folder or added from anywhere on disk
+    pub fn file(path: PathBuf) -> Sound {
+        Sound::File {
+            path,
+            category: None,
+        }
+    }
+
+    // The name used to select the sound, ie from the command line. Files in a category are named "Category/file.wav"
+    pub fn name(&self) -> String {
+        match self {
+            Sound::File { path, category } => {
+                let file_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                match category {
+                    Some(category) => category.clone() + "/" + &file_name,
+                    None => file_name,
+                }
+            }
+            Sound::Pack(pack) => pack.category.clone(),
+            Sound::Embedded(sound) => sound.name.to_string(),
+            Sound::Synth(voice) => voice.name().to_string(),
+        }
+    }
+
+    // The name shown in the Status menu along with where the sound came from
+    pub fn label(&self) -> String {
+        let origin = match self {
+            Sound::File { path, category } => {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let dir = if category.is_some() {
+                    dir.parent().unwrap_or(dir)
+                } else {
+                    dir
+                };
+                dir.display().to_string()
+            }
+            Sound::Pack(pack) => pack
+                .normal
+                .parent()
+                .and_then(Path::parent)
+                .map(|dir| dir.display().to_string() + " pack")
+                .unwrap_or_default(),
+            Sound::Embedded(_) => "built in".to_string(),
+            Sound::Synth(_) => "synth".to_string(),
+        };
+        self.name() + " (" + &origin + ")"
+    }
+
+    // The name shown under the sound's group in the Sound Selection tree
+    pub fn short_name(&self) -> String {
+        match self {
+            Sound::File { path, .. } => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            _ => self.name(),
+        }
+    }
+
+    // The group the sound is listed under in the Sound Selection tree, files at the top of a sound folder have none
+    pub fn group(&self) -> Option<String> {
+        match self {
+            Sound::File { category, .. } => category.clone(),
+            Sound::Pack(pack) => Some(pack.category.clone()),
+            Sound::Embedded(_) => Some("Built in".to_string()),
+            Sound::Synth(_) => Some("Synth".to_string()),
+        }
+    }
+
+    // Open the sound for playback. A pack plays a different file for each kind of click, and pitch (semitones) and
+    // decay (ms) only apply to the synth voices
+    pub fn open(&self, kind: TickKind, pitch: f64, decay: f64) -> Result<SoundSource, Report> {
+        match self {
+            Sound::File { path, .. } => open_path(path),
+            Sound::Pack(pack) => open_path(pack.role_path(kind)),
+            Sound::Embedded(sound) => decode(Cursor::new(sound.bytes)),
+            Sound::Synth(voice) => Ok(Box::new(Synth::new(*voice, pitch, decay))),
+        }
+    }
+}
+
+fn open_path(path: &Path) -> Result<SoundSource, Report> {
+    let file = match File::open(path) {
+        Ok(value) => io::BufReader::new(value),
+        Err(_) => return Err(eyre!("Error: Problem loading sound")),
+    };
+    decode(file)
+}
+
+fn decode<R: Read + Seek + Send + Sync + 'static>(data: R) -> Result<SoundSource, Report> {
+    match Decoder::new(data) {
+        Ok(value) => Ok(Box::new(value.convert_samples())),
+        Err(_) => Err(eyre!("Error: Problem decoding sound")),
+    }
+}
+
+// The folders to look for sounds in, in the order they are searched
+pub fn sound_dirs(assets_dir: Option<&str>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = assets_dir {
+        dirs.push(PathBuf::from(dir));
+    }
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(value) if !value.is_empty() => Some(PathBuf::from(value)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")),
+    };
+    if let Some(data_home) = data_home {
+        dirs.push(data_home.join("readymetronome").join("sounds"));
+    }
+    if let Some(exe_dir) = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push(exe_dir.join("assets"));
+    }
+    dirs
+}
+
+// The result of searching the sound folders, along with the reason each file that can't be used was skipped
+pub struct SoundScan {
+    pub sounds: Vec<Sound>,
+    pub skipped: Vec<String>,
+}
+
+// List every sound in the sound folders, followed by the embedded samples and the synth voices. Folders that don't
+// exist are skipped, and folders or files that can't be read are skipped with a reason. Each folder lists its own
+// files before its categories, all in name order so the list is the same on every system
+pub fn find_sounds(dirs: &[PathBuf]) -> Result<SoundScan, Report> {
+    let mut scan = SoundScan {
+        sounds: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for dir in dirs {
+        let (files, folders) = match scan.read_folder(dir) {
+            Ok(value) => value,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => {
+                scan.skip_unreadable(dir, error);
+                continue;
+            }
+        };
+        scan.add_files(files, None);
+        for folder in folders {
+            scan.add_category(&folder);
+        }
+    }
+    let mut sounds = scan.sounds;
+    for embedded in EMBEDDED_SOUNDS {
+        if !sounds.iter().any(|found| found.name() == embedded.name) {
+            sounds.push(Sound::Embedded(embedded));
+        }
+    }
+    // the synth voices are always available so there is something to play without any sound files
+    sounds.extend(SynthVoice::ALL.into_iter().map(Sound::Synth));
+    Ok(SoundScan {
+        sounds,
+        skipped: scan.skipped,
+    })
+}
+
+impl SoundScan {
+    // Add the files of a folder that are actually sounds, so picking one can't stop the metronome
+    fn add_files(&mut self, files: Vec<PathBuf>, category: Option<&str>) {
+        for path in files {
+            let sound = Sound::File {
+                path,
+                category: category.map(str::to_string),
+            };
+            if self.sounds.iter().any(|found| found.name() == sound.name()) {
+                continue;
+            }
+            match probe_sound_file(&sound) {
+                Ok(_) => self.sounds.push(sound),
+                Err(error) => self.skipped.push(error.to_string()),
+            }
+        }
+    }
+
+    // Add a category folder, and its pack if it has a manifest. Folders inside a category are not searched
+    fn add_category(&mut self, folder: &Path) {
+        let mut files = match self.read_folder(folder) {
+            Ok((files, _)) => files,
+            Err(error) => return self.skip_unreadable(folder, error),
+        };
+        let manifest_path = folder.join(PACK_MANIFEST);
+        files.retain(|path| *path != manifest_path);
+        let manifest = match fs::read_to_string(&manifest_path) {
+            Ok(contents) => match toml::from_str::<PackManifest>(&contents) {
+                Ok(value) => Some(value),
+                Err(error) => {
+                    self.skipped
+                        .push(manifest_path.display().to_string() + ": " + error.message());
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let category = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.name.clone())
+            .unwrap_or_else(|| folder.file_name().unwrap().to_string_lossy().to_string());
+
+        let first_sound = self.sounds.len();
+        self.add_files(files, Some(&category));
+        if let Some(manifest) = manifest {
+            match self.pack_from_manifest(&manifest, folder, &category, first_sound) {
+                Ok(Some(pack)) => self.sounds.insert(first_sound, Sound::Pack(pack)),
+                Ok(None) => {}
+                Err(error) => self
+                    .skipped
+                    .push(manifest_path.display().to_string() + ": " + &error),
+            }
+        }
+    }
+
+    // The files and folders in a folder, in name order. Entries that can't be read are skipped so one bad file doesn't
+    // hide the rest of the folder
+    fn read_folder(&mut self, dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), io::Error> {
+        let mut files = Vec::new();
+        let mut folders = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(error) => {
+                    self.skip_unreadable(dir, error);
+                    continue;
+                }
+            };
+            if path.is_file() {
+                files.push(path);
+            } else if path.is_dir() {
+                folders.push(path);
+            }
+        }
+        files.sort();
+        folders.sort();
+        Ok((files, folders))
+    }
+
+    fn skip_unreadable(&mut self, path: &Path, error: io::Error) {
+        self.skipped
+            .push(path.display().to_string() + ": " + &error.to_string());
+    }
+
+    // Work out which file plays each role, every file named in the manifest has to be one of the category's sounds
+    fn pack_from_manifest(
+        &self,
+        manifest: &PackManifest,
+        folder: &Path,
+        category: &str,
+        first_sound: usize,
+    ) -> Result<Option<SoundPack>, String> {
+        let role = |file_name: &Option<String>| -> Result<Option<PathBuf>, String> {
+            let Some(file_name) = file_name else {
+                return Ok(None);
+            };
+            let path = folder.join(file_name);
+            let found = self.sounds[first_sound..]
+                .iter()
+                .any(|sound| matches!(sound, Sound::File { path: found, .. } if *found == path));
+            match found {
+                true => Ok(Some(path)),
+                false => Err(file_name.clone() + " is not one of the sounds in the folder"),
+            }
+        };
+        let accent = role(&manifest.accent)?;
+        let subdivision = role(&manifest.subdivision)?;
+        let Some(normal) = role(&manifest.normal)?.or(accent.clone()) else {
+            return Ok(None);
+        };
+        Ok(Some(SoundPack {
+            category: category.to_string(),
+            accent: accent.unwrap_or(normal.clone()),
+            subdivision: subdivision.unwrap_or(normal.clone()),
+            normal,
+        }))
+    }
+}
+
+// Add a sound that isn't in one of the sound folders to the list, after the uncategorised sound files. Returns its
+// index in the list
+pub fn insert_file(sounds: &mut Vec<Sound>, sound: Sound) -> usize {
+    match sounds.iter().position(|found| *found == sound) {
+        Some(index) => index,
+        None => {
+            let index = sounds
+                .iter()
+                .position(|found| !matches!(found, Sound::File { category: None, .. }))
+                .unwrap_or(sounds.len());
+            sounds.insert(index, sound);
+            index
+        }
+    }
+}
+
+// Watches the sound folders for files being added, removed or changed
+pub struct SoundWatcher {
+    watcher: RecommendedWatcher, // stops watching when dropped
+    events: Receiver<notify::Result<notify::Event>>,
+    last_change: Option<Instant>,
+    dirs: Vec<PathBuf>,
+    missing: Vec<PathBuf>, // sound folders that don't exist yet, watched through their nearest parent
+    pub warnings: Vec<String>, // why a sound folder couldn't be watched
+}
+
+impl SoundWatcher {
+    // Watch every sound folder, including its categories. A folder that doesn't exist yet is watched for once it is
+    // created, and a folder that can't be watched is skipped with a warning so the others are still watched
+    pub fn new(dirs: &[PathBuf]) -> Result<SoundWatcher, Report> {
+        let (sender, events) = mpsc::channel();
+        let mut sound_watcher = SoundWatcher {
+            watcher: notify::recommended_watcher(sender)?,
+            events,
+            last_change: None,
+            dirs: dirs.to_vec(),
+            missing: Vec::new(),
+            warnings: Vec::new(),
+        };
+        for dir in dirs {
+            match dir.is_dir() {
+                true => sound_watcher.watch(dir, RecursiveMode::Recursive),
+                false => sound_watcher.missing.push(dir.clone()),
+            }
+        }
+        sound_watcher.watch_missing();
+        Ok(sound_watcher)
+    }
+
+    // Whether the folders changed and have since settled, so they are ready to be searched again
+    pub fn changed(&mut self, now: Instant) -> bool {
+        let dirs = &self.dirs;
+        for event in self.events.try_iter().flatten() {
+            // the parents of missing folders can change for other reasons, only changes on the way to a sound folder
+            // or inside one count
+            let in_sound_dir = event.paths.iter().any(|path| {
+                dirs.iter()
+                    .any(|dir| path.starts_with(dir) || dir.starts_with(path))
+            });
+            if in_sound_dir && !matches!(event.kind, EventKind::Access(_)) {
+                self.last_change = Some(now);
+            }
+        }
+        if self.last_change == Some(now) {
+            self.watch_missing();
+        }
+        match self.last_change {
+            Some(last_change) if now.duration_since(last_change) >= WATCH_SETTLE_TIME => {
+                self.last_change = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Start watching the missing folders that have been created, and watch the nearest parent of the rest so their
+    // creation is seen
+    fn watch_missing(&mut self) {
+        for dir in std::mem::take(&mut self.missing) {
+            if dir.is_dir() {
+                self.watch(&dir, RecursiveMode::Recursive);
+                continue;
+            }
+            if let Some(parent) = dir.ancestors().skip(1).find(|parent| parent.is_dir()) {
+                self.watch(parent, RecursiveMode::NonRecursive);
+            }
+            self.missing.push(dir);
+        }
+    }
+
+    fn watch(&mut self, dir: &Path, mode: RecursiveMode) {
+        if let Err(error) = self.watcher.watch(dir, mode) {
+            self.warnings
+                .push(dir.display().to_string() + ": " + &error.to_string());
+        }
+    }
+}
+
+// Check that a file added by the user decodes and is short enough to click with
+pub fn load_sound_file(path: &str) -> Result<Sound, Report> {
+    let path = PathBuf::from(path);
+    if !path.is_file() {
+        return Err(eyre!("Could not find the sound file {}", path.display()));
+    }
+    let sound = Sound::file(path);
+    probe_sound_file(&sound)?;
+    Ok(sound)
+}
+
+// Decode the start of a sound file to make sure it can be played and isn't too long
+fn probe_sound_file(sound: &Sound) -> Result<(), Report> {
+    let Sound::File { path, .. } = sound else {
+        return Ok(());
+    };
+    let file = match File::open(path) {
+        Ok(value) => io::BufReader::new(value),
+        Err(error) => return Err(eyre!("Could not open {}: {}", path.display(), error)),
+    };
+    let decoder = match Decoder::new(file) {
+        Ok(value) => value,
+        Err(_) => {
+            return Err(eyre!(
+                "{} is not a WAV, FLAC, OGG or MP3 file",
+                path.display()
+            ))
+        }
+    };
+
+    // only decode as far as the limit so a whole song isn't decoded just to reject it
+    let samples_per_second = decoder.sample_rate() as u64 * decoder.channels() as u64;
+    let limit = samples_per_second * MAX_SOUND_LENGTH.as_secs() + 1;
+    if decoder.take(limit as usize).count() as u64 == limit {
+        return Err(eyre!(
+            "{} is too long for a click, sounds must be under {} seconds",
+            path.display(),
+            MAX_SOUND_LENGTH.as_secs()
+        ));
+    }
+    Ok(())
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the embedded samples should decode without touching the file system
+    #[test]
+    fn sounds_embedded_decode() {
+        for embedded in EMBEDDED_SOUNDS {
+            assert!(Sound::Embedded(embedded)
+                .open(TickKind::Normal, 0.0, 40.0)
+                .is_ok());
+        }
+    }
+
+    // a file in a sound folder should take the place of the embedded sample with the same name
+    #[test]
+    fn sounds_find_sounds() {
+        let dir = env::temp_dir().join("readymetronome_sounds_find_sounds");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("TronicClick1.wav"), EMBEDDED_SOUNDS[1].bytes).unwrap();
+
+        let sounds = find_sounds(&[dir.clone(), dir.join("missing")])
+            .unwrap()
+            .sounds;
+        assert_eq!(sounds[0], Sound::file(dir.join("TronicClick1.wav")));
+        assert_eq!(sounds[1], Sound::Embedded(EMBEDDED_SOUNDS[0]));
+        assert_eq!(sounds.len(), 2 + SynthVoice::ALL.len());
+        assert_eq!(sounds[1].label(), "EmeryBoardClick.wav (built in)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // files that aren't sounds should be skipped with a reason, and sounds listed in name order
+    #[test]
+    fn sounds_find_sounds_skips_other_files() {
+        let dir = env::temp_dir().join("readymetronome_sounds_skips_other_files");
+        fs::create_dir_all(dir.join("Empty category")).unwrap();
+        fs::write(dir.join("b.wav"), EMBEDDED_SOUNDS[0].bytes).unwrap();
+        fs::write(dir.join("a.wav"), EMBEDDED_SOUNDS[1].bytes).unwrap();
+        fs::write(dir.join(".DS_Store"), [0, 0, 0, 1]).unwrap();
+        fs::write(dir.join("README.md"), "not a sound").unwrap();
+
+        let scan = find_sounds(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(scan.sounds[0].name(), "a.wav");
+        assert_eq!(scan.sounds[1].name(), "b.wav");
+        assert_eq!(
+            scan.sounds.len(),
+            2 + EMBEDDED_SOUNDS.len() + SynthVoice::ALL.len()
+        );
+        assert_eq!(scan.skipped.len(), 2);
+        assert!(scan.skipped[0].contains(".DS_Store"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // sub-folders should become categories, with a pack when they have a manifest
+    #[test]
+    fn sounds_find_sounds_categories() {
+        let dir = env::temp_dir().join("readymetronome_sounds_categories");
+        let woodblocks = dir.join("Woodblocks");
+        fs::create_dir_all(&woodblocks).unwrap();
+        fs::create_dir_all(dir.join("Electronic")).unwrap();
+        fs::write(woodblocks.join("high.wav"), EMBEDDED_SOUNDS[0].bytes).unwrap();
+        fs::write(woodblocks.join("low.wav"), EMBEDDED_SOUNDS[1].bytes).unwrap();
+        fs::write(
+            woodblocks.join(PACK_MANIFEST),
+            "name = \"Wood Blocks\"\naccent = \"high.wav\"\nnormal = \"low.wav\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("Electronic/blip.wav"), EMBEDDED_SOUNDS[1].bytes).unwrap();
+        fs::write(dir.join("Electronic/pack.toml"), "normal = \"missing.wav\"").unwrap();
+
+        let scan = find_sounds(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(scan.sounds[0].name(), "Electronic/blip.wav");
+        assert_eq!(
+            scan.sounds[1],
+            Sound::Pack(SoundPack {
+                category: "Wood Blocks".to_string(),
+                accent: woodblocks.join("high.wav"),
+                normal: woodblocks.join("low.wav"),
+                subdivision: woodblocks.join("low.wav"),
+            })
+        );
+        assert_eq!(scan.sounds[2].name(), "Wood Blocks/high.wav");
+        assert_eq!(scan.sounds[3].group().unwrap(), "Wood Blocks");
+        assert_eq!(scan.skipped.len(), 1);
+        assert!(scan.skipped[0].contains("missing.wav is not one of the sounds"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a category that can't be read (ie deleted while it was being searched) should be skipped with a reason rather
+    // than stop the search
+    #[test]
+    fn sounds_unreadable_category() {
+        let dir = env::temp_dir().join("readymetronome_sounds_unreadable_category");
+        fs::create_dir_all(&dir).unwrap();
+        let mut scan = SoundScan {
+            sounds: Vec::new(),
+            skipped: Vec::new(),
+        };
+        scan.add_category(&dir.join("Gone"));
+        assert!(scan.sounds.is_empty());
+        assert_eq!(scan.skipped.len(), 1);
+        assert!(scan.skipped[0].contains("Gone"));
+
+        // a sound folder that is a file can't be searched either
+        fs::write(dir.join("not a folder"), "").unwrap();
+        let scan = find_sounds(&[dir.join("not a folder")]).unwrap();
+        assert_eq!(scan.skipped.len(), 1);
+        assert!(scan.skipped[0].contains("not a folder"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Check the watcher until it has seen a change, returning when it saw it. Events arrive from another thread so
+    // they are waited for rather than expected straight away
+    fn wait_for_change(watcher: &mut SoundWatcher) -> Instant {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let now = Instant::now();
+            assert!(!watcher.changed(now));
+            if watcher.last_change.is_some() {
+                return now;
+            }
+            assert!(now < deadline, "the watcher didn't see the change");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // the watcher should only report a change once the folder has gone quiet
+    #[test]
+    fn sounds_watcher() {
+        let dir = env::temp_dir().join("readymetronome_sounds_watcher");
+        fs::create_dir_all(&dir).unwrap();
+        let mut watcher = SoundWatcher::new(std::slice::from_ref(&dir)).unwrap();
+        assert!(!watcher.changed(Instant::now()));
+
+        fs::write(dir.join("click.wav"), EMBEDDED_SOUNDS[0].bytes).unwrap();
+        let now = wait_for_change(&mut watcher);
+        assert!(watcher.changed(now + WATCH_SETTLE_TIME));
+        assert!(!watcher.changed(now + WATCH_SETTLE_TIME * 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a sound folder created after starting should be watched from then on
+    #[test]
+    fn sounds_watcher_missing_folder() {
+        let parent = env::temp_dir().join("readymetronome_sounds_watcher_missing");
+        let _ = fs::remove_dir_all(&parent);
+        fs::create_dir_all(&parent).unwrap();
+        let dir = parent.join("readymetronome/sounds");
+        let mut watcher = SoundWatcher::new(std::slice::from_ref(&dir)).unwrap();
+        assert!(watcher.warnings.is_empty());
+
+        // other files next to the folder aren't sounds
+        fs::write(parent.join("other.txt"), "").unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        let now = wait_for_change(&mut watcher);
+        assert!(watcher.changed(now + WATCH_SETTLE_TIME));
+        assert!(watcher.missing.is_empty());
+
+        fs::write(dir.join("click.wav"), EMBEDDED_SOUNDS[0].bytes).unwrap();
+        let now = wait_for_change(&mut watcher);
+        assert!(watcher.changed(now + WATCH_SETTLE_TIME));
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    // sound files should be rejected if they don't decode or are too long to click with
+    #[test]
+    fn sounds_load_sound_file() {
+        let dir = env::temp_dir().join("readymetronome_sounds_load_sound_file");
+        fs::create_dir_all(&dir).unwrap();
+        let click = dir.join("click.wav");
+        fs::write(&click, EMBEDDED_SOUNDS[0].bytes).unwrap();
+        let text = dir.join("README.md");
+        fs::write(&text, "not a sound").unwrap();
+        let long = dir.join("long.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&long, spec).unwrap();
+        for _ in 0..8_000 * 3 {
+            writer.write_sample(0_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        assert_eq!(
+            load_sound_file(click.to_str().unwrap()).unwrap(),
+            Sound::file(click.clone())
+        );
+        assert!(load_sound_file(text.to_str().unwrap()).is_err());
+        let error = load_sound_file(long.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("too long"));
+        assert!(load_sound_file(dir.join("missing.wav").to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+/// Synth.rs holds the built in synthesized click voices. They are generated on the fly as rodio Sources so the
+/// metronome always has something to play, even when no sound files can be found.
+// rodio Source docs: https://docs.rs/rodio/latest/rodio/source/trait.Source.html
+use rodio::Source;
+use std::{f32::consts::TAU, ops::RangeInclusive, time::Duration};
+
+const SYNTH_SAMPLE_RATE: u32 = 44_100;
+
+// The tunable range of the synth voices, pitch in semitones and decay in milliseconds
+pub const PITCH_RANGE: RangeInclusive<f64> = -24.0..=24.0;
+pub const DECAY_RANGE: RangeInclusive<f64> = 5.0..=1000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SynthVoice {
+    SineBlip,
+    SquareBeep,
+    NoiseWoodblock,
+    FmCowbell,
+}
+
+impl SynthVoice {
+    pub const ALL: [SynthVoice; 4] = [
+        SynthVoice::SineBlip,
+        SynthVoice::SquareBeep,
+        SynthVoice::NoiseWoodblock,
+        SynthVoice::FmCowbell,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SynthVoice::SineBlip => "Sine blip",
+            SynthVoice::SquareBeep => "Square beep",
+            SynthVoice::NoiseWoodblock => "Noise woodblock",
+            SynthVoice::FmCowbell => "FM cowbell",
+        }
+    }
+
+    // The pitch of the voice in Hz before it is tuned
+    fn base_frequency(&self) -> f32 {
+        match self {
+            SynthVoice::SineBlip => 1000.0,
+            SynthVoice::SquareBeep => 880.0,
+            SynthVoice::NoiseWoodblock => 1800.0,
+            SynthVoice::FmCowbell => 560.0,
+        }
+    }
+}
+
+// A single hit of a synth voice. pitch is in semitones away from the voice's base pitch and decay is the time in
+// milliseconds for the hit to fall to about a third of its starting level
+pub struct Synth {
+    voice: SynthVoice,
+    frequency: f32,
+    decay: f32,
+    position: usize,
+    length: usize,
+    noise: u32,
+    resonator: (f32, f32),
+}
+
+impl Synth {
+    pub fn new(voice: SynthVoice, pitch: f64, decay: f64) -> Synth {
+        let decay = (decay / 1000.0) as f32;
+        Synth {
+            voice,
+            frequency: voice.base_frequency() * 2_f32.powf(pitch as f32 / 12.0),
+            decay,
+            position: 0,
+            // stop once the hit has decayed to silence (about -43dB)
+            length: (decay * 5.0 * SYNTH_SAMPLE_RATE as f32) as usize + 1,
+            noise: 0x1234_5678,
+            resonator: (0.0, 0.0),
+        }
+    }
+
+    // xorshift white noise between -1.0 and 1.0
+    fn next_noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+impl Iterator for Synth {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.length {
+            return None;
+        }
+        let time = self.position as f32 / SYNTH_SAMPLE_RATE as f32;
+        let envelope = (-time / self.decay).exp();
+        let phase = TAU * self.frequency * time;
+        self.position += 1;
+
+        let value = match self.voice {
+            SynthVoice::SineBlip => phase.sin(),
+            SynthVoice::SquareBeep => phase.sin().signum() * 0.5,
+            SynthVoice::NoiseWoodblock => {
+                // a short burst of noise ringing a two pole resonator tuned to the pitch
+                let burst = if time < 0.002 { self.next_noise() } else { 0.0 };
+                let w = TAU * self.frequency / SYNTH_SAMPLE_RATE as f32;
+                let r = 0.995_f32;
+                let (y1, y2) = self.resonator;
+                let y = burst + 2.0 * r * w.cos() * y1 - r * r * y2;
+                self.resonator = (y, y1);
+                (y * 0.1).clamp(-1.0, 1.0)
+            }
+            SynthVoice::FmCowbell => {
+                // inharmonic modulator whose depth falls with the envelope, which gives the metallic clank
+                let modulator = (TAU * self.frequency * 1.48 * time).sin();
+                (phase + 2.0 * envelope * modulator).sin() * 0.8
+            }
+        };
+        Some(value * envelope)
+    }
+}
+
+impl Source for Synth {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.length - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SYNTH_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(
+            self.length as f64 / SYNTH_SAMPLE_RATE as f64,
+        ))
+    }
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every voice should make a sound that stays in range and dies away within its decay time
+    #[test]
+    fn synth_voices() {
+        for voice in SynthVoice::ALL {
+            let samples: Vec<f32> = Synth::new(voice, 0.0, 50.0).collect();
+            assert_eq!(samples.len(), 11_026);
+            assert!(samples.iter().all(|value| value.abs() <= 1.0));
+            assert!(samples.iter().any(|value| value.abs() > 0.05));
+            assert!(samples[samples.len() - 100..]
+                .iter()
+                .all(|value| value.abs() < 0.01));
+        }
+    }
+
+    // pitch is measured in semitones, so +12 doubles the frequency
+    #[test]
+    fn synth_pitch() {
+        let synth = Synth::new(SynthVoice::SineBlip, 12.0, 50.0);
+        assert_eq!(synth.frequency, 2000.0);
+    }
+}
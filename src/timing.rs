@@ -0,0 +1,155 @@
+/// Timing.rs measures how accurately the metronome keeps time. In debug mode every tick records when it was
+/// scheduled to play and when it actually did, the difference being its jitter. Running statistics are kept as the
+/// ticks come in so the Status panel doesn't have to go over every tick to show them, and the whole log can be
+/// written out as CSV to look at afterwards. Each start of the metronome is a new run, numbered in the CSV so runs
+/// can be told apart.
+use color_eyre::{Report, Result};
+use std::{fs, path::Path, time::Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TickTiming {
+    pub run: u32,
+    pub scheduled: Instant,
+    pub actual: Instant,
+}
+
+impl TickTiming {
+    // How late (or early when negative) the tick was in milliseconds
+    pub fn jitter_ms(&self) -> f64 {
+        match self.actual >= self.scheduled {
+            true => self.actual.duration_since(self.scheduled).as_secs_f64() * 1000.0,
+            false => -(self.scheduled.duration_since(self.actual).as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+// Jitter statistics in milliseconds, the mean and variance are kept with Welford's method so they can be updated
+// one tick at a time: https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    sum_squares: f64, // sum of squared differences from the mean
+}
+
+impl JitterStats {
+    fn add(&mut self, jitter: f64) {
+        if self.count == 0 {
+            self.min = jitter;
+            self.max = jitter;
+        }
+        self.count += 1;
+        self.min = self.min.min(jitter);
+        self.max = self.max.max(jitter);
+        let delta = jitter - self.mean;
+        self.mean += delta / self.count as f64;
+        self.sum_squares += delta * (jitter - self.mean);
+    }
+
+    // Population standard deviation
+    pub fn stddev(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => (self.sum_squares / count as f64).sqrt(),
+        }
+    }
+}
+
+// Every tick recorded while debugging, along with their statistics
+#[derive(Clone, Debug, Default)]
+pub struct TimingLog {
+    ticks: Vec<TickTiming>,
+    stats: JitterStats,
+    run: u32,
+}
+
+impl TimingLog {
+    pub fn record(&mut self, scheduled: Instant, actual: Instant) {
+        let timing = TickTiming {
+            run: self.run,
+            scheduled,
+            actual,
+        };
+        self.stats.add(timing.jitter_ms());
+        self.ticks.push(timing);
+    }
+
+    // Number the ticks from here on as the next run, called whenever the metronome starts
+    pub fn start_run(&mut self) {
+        self.run += 1;
+    }
+
+    pub fn ticks(&self) -> &[TickTiming] {
+        &self.ticks
+    }
+
+    // None until a tick has been recorded
+    pub fn stats(&self) -> Option<JitterStats> {
+        match self.stats.count {
+            0 => None,
+            _ => Some(self.stats),
+        }
+    }
+
+    // Write the run, scheduled and actual time (from the first scheduled tick) and jitter of each tick as CSV
+    pub fn write_csv(&self, path: &Path) -> Result<(), Report> {
+        let mut csv = "run,tick,scheduled_ms,actual_ms,jitter_ms\n".to_string();
+        if let Some(first) = self.ticks.first() {
+            let start = first.scheduled;
+            for (index, tick) in self.ticks.iter().enumerate() {
+                let scheduled = tick.scheduled.duration_since(start).as_secs_f64() * 1000.0;
+                let actual = tick.actual.duration_since(start).as_secs_f64() * 1000.0;
+                csv += &format!(
+                    "{},{},{:.3},{:.3},{:.3}\n",
+                    tick.run,
+                    index,
+                    scheduled,
+                    actual,
+                    tick.jitter_ms()
+                );
+            }
+        }
+        fs::write(path, csv)?;
+        Ok(())
+    }
+}
+
+// Tests ---------------------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timing_stats_and_csv() {
+        let mut log = TimingLog::default();
+        assert!(log.stats().is_none());
+        let start = Instant::now();
+        log.start_run();
+        for (tick, late_ms) in [1, 3, 2, 2].into_iter().enumerate() {
+            if tick == 2 {
+                log.start_run();
+            }
+            let scheduled = start + Duration::from_millis(500 * tick as u64);
+            log.record(scheduled, scheduled + Duration::from_millis(late_ms));
+        }
+        let stats = log.stats().unwrap();
+        assert_eq!(stats.count, 4);
+        assert!((stats.min - 1.0).abs() < 0.0001);
+        assert!((stats.max - 3.0).abs() < 0.0001);
+        assert!((stats.mean - 2.0).abs() < 0.0001);
+        assert!((stats.stddev() - 0.5_f64.sqrt()).abs() < 0.0001);
+
+        let path = std::env::temp_dir().join("readymetronome_timing_test.csv");
+        log.write_csv(&path).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "run,tick,scheduled_ms,actual_ms,jitter_ms");
+        assert_eq!(lines[1], "1,0,0.000,1.000,1.000");
+        assert_eq!(lines[3], "2,2,1000.000,1002.000,2.000");
+        assert_eq!(lines[4], "2,3,1500.000,1502.000,2.000");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,37 @@
 /// This file controls the ratatui user interface display. It conditionally renders different screens based on the state
 /// defined in App.rs
 /// This is loosely based on the JSON Editor tutorial for ratatui. Tutorial found here https://ratatui.rs/tutorials/json-editor/ui/
-use crate::app::{App, CurrentScreen, CurrentlyEditing};
+use crate::{
+    app::{App, ClickAreas, CurrentScreen, CurrentlyEditing, MIN_CALIBRATION_TAPS},
+    big_text, keymap,
+    sounds::MAX_SOUND_LENGTH,
+};
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Style, Stylize},
-    text::{Line, Span, Text},
-    widgets::{Block, Borders, Clear, List, ListItem, Paragraph},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
     Frame,
 };
+use std::sync::atomic::Ordering;
 
 // This is the function to render the UI to the screen
 pub fn ui(f: &mut Frame, app: &mut App) {
+    // stage mode takes over the whole screen, until there is something that needs an answer (ie quitting)
+    if app.stage_mode
+        && matches!(
+            app.current_screen,
+            CurrentScreen::Main | CurrentScreen::Editing
+        )
+        && app.currently_editing.is_none()
+    {
+        // nothing under the big digits can be clicked
+        app.click_areas = ClickAreas::default();
+        stage(f, app);
+        help(f, app);
+        return;
+    }
+
     // pop up block to use for editing / quit dialog
     let popup_block = Block::default()
         .title("Editing Value")
@@ -24,11 +44,12 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     let quit_style = Style::default().fg(Color::Red);
     let error_style = Style::default().fg(Color::Red);
 
-    // this defines the overall layout into three sections with the middle one being resizeable
+    // this defines the overall layout into four sections with the menus being resizeable
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Length(3),
+            Constraint::Length(5),
             Constraint::Min(1),
             Constraint::Length(3),
         ])
@@ -39,14 +60,81 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         .borders(Borders::ALL)
         .style(Style::default());
 
-    let title = Paragraph::new(Text::styled(
+    // when a setlist or song map is loaded the current song / section and its position are shown next to the app name
+    let mut title_spans = vec![Span::styled(
         "Ready Metronome",
         Style::default().fg(Color::Green),
-    ))
-    .block(title_block);
+    )];
+    if let Some(song) = app.get_setlist_string() {
+        title_spans.push(Span::raw(" | "));
+        title_spans.push(Span::styled(song, Style::default().fg(Color::LightYellow)));
+    }
+    if let Some(section) = app.get_section_string() {
+        title_spans.push(Span::raw(" | "));
+        title_spans.push(Span::styled(section, Style::default().fg(Color::LightCyan)));
+    }
+    if app.get_muted() {
+        title_spans.push(Span::styled(
+            " | Muted",
+            Style::default().fg(Color::LightRed),
+        ));
+    }
+    if app.get_is_running() && app.settings.visual_bar_count.load(Ordering::Relaxed) == 0 {
+        title_spans.push(Span::styled(
+            " | Count-in",
+            Style::default().fg(Color::LightRed),
+        ));
+    }
+    // the click carries on by itself once the audio device is back, so this is only a warning
+    if let Some(warning) = app.get_audio_warning() {
+        title_spans.push(Span::raw(" | "));
+        title_spans.push(Span::styled(warning, Style::default().fg(Color::LightRed)));
+    }
+    let title = Paragraph::new(Line::from(title_spans)).block(title_block);
 
     f.render_widget(title, chunks[0]);
 
+    // Beat Indicator --------------------------------------------------------------------------------------------------
+    // one cell per beat of the bar with the current beat lit up, so the metronome can be followed without hearing it
+    let indicator = app.get_beat_indicator();
+    let beat_block =
+        Block::default()
+            .title("Beat")
+            .borders(Borders::ALL)
+            .style(match indicator.flash {
+                true => Style::default().bg(Color::White),
+                false => Style::default(),
+            });
+    let beat_area = beat_block.inner(chunks[1]);
+    f.render_widget(beat_block, chunks[1]);
+    let beat_cells = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(vec![
+            Constraint::Ratio(1, indicator.beats as u32);
+            indicator.beats as usize
+        ])
+        .split(beat_area);
+    app.click_areas.beat_cells = beat_cells.to_vec();
+    for (beat, cell) in beat_cells.iter().enumerate() {
+        let beat = beat as u64;
+        let accent = beat < 64 && indicator.accents & (1 << beat) != 0;
+        let color = if accent {
+            Color::LightRed
+        } else {
+            Color::LightGreen
+        };
+        let style = match indicator.current == Some(beat) {
+            true => Style::default().bg(color).fg(Color::Black),
+            false if accent => Style::default().fg(color),
+            false => Style::default().fg(Color::DarkGray),
+        };
+        let beat_text = Paragraph::new((beat + 1).to_string())
+            .alignment(Alignment::Center)
+            .style(style)
+            .block(Block::default().borders(Borders::ALL).border_style(style));
+        f.render_widget(beat_text, *cell);
+    }
+
     // Main screen -----------------------------------------------------------------------------------------------------
     // for the main menu screen we will use a widgets::List and ListState which we define from items in main.rs
     // loading in vector of items from main_menu and edit_menu for rendering
@@ -69,46 +157,39 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     let main_chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(25), Constraint::Percentage(75)])
-        .split(chunks[1]);
+        .split(chunks[2]);
 
     f.render_stateful_widget(main_list, main_chunks[0], &mut app.main_menu.state);
+    app.click_areas.main_menu = main_chunks[0];
+    app.click_areas.right_panel = main_chunks[1];
 
     // Right Panel -----------------------------------------------------------------------------------------------------
-    let right_panel_items: Vec<ListItem> = if app.current_screen != CurrentScreen::SoundSelection {
-        app.edit_menu
-            .items
-            .iter()
-            .map(|i| ListItem::new(i.as_str()))
-            .collect()
-    } else {
-        app.sound_selection_menu
-            .items
-            .iter()
-            .map(|i| ListItem::new(i.as_str()))
-            .collect()
+    // the right panel shows the status list unless we are picking a sound or output device, or editing the song map
+    let (right_panel_title, right_panel_menu) = match app.current_screen {
+        CurrentScreen::SoundSelection => ("Sound Selection", &mut app.sound_selection_menu),
+        CurrentScreen::SongMap => ("Song Map", &mut app.song_map_menu),
+        CurrentScreen::AudioSettings => ("Audio Output", &mut app.audio_menu),
+        _ => ("Status", &mut app.edit_menu),
     };
+    let right_panel_items: Vec<ListItem> = right_panel_menu
+        .items
+        .iter()
+        .map(|i| ListItem::new(i.as_str()))
+        .collect();
     let right_panel_list = List::new(right_panel_items)
         .block(
             Block::default()
-                .title(if app.current_screen == CurrentScreen::SoundSelection {
-                    "Sound Selection"
-                } else {
-                    "Status"
-                })
+                .title(right_panel_title)
                 .borders(Borders::ALL),
         )
         .style(Style::default().fg(Color::White))
         .highlight_style(active_style);
 
-    if app.current_screen == CurrentScreen::SoundSelection {
-        f.render_stateful_widget(
-            right_panel_list,
-            main_chunks[1],
-            &mut app.sound_selection_menu.state,
-        );
-    } else {
-        f.render_stateful_widget(right_panel_list, main_chunks[1], &mut app.edit_menu.state);
-    }
+    f.render_stateful_widget(
+        right_panel_list,
+        main_chunks[1],
+        &mut right_panel_menu.state,
+    );
 
     // Editing Value Pop Up --------------------------------------------------------------------------------------------
     if let Some(editing) = app.currently_editing {
@@ -153,6 +234,69 @@ pub fn ui(f: &mut Frame, app: &mut App) {
                 original_block = Block::default().title("Current Bpm").borders(Borders::ALL);
                 original_text = Paragraph::new(app.get_bpm().to_string()).block(original_block);
             }
+            CurrentlyEditing::SynthPitch => {
+                key_block = Block::default()
+                    .title("Enter New Synth Pitch (semitones)")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Current Synth Pitch")
+                    .borders(Borders::ALL);
+                original_text =
+                    Paragraph::new(app.get_synth_pitch().to_string()).block(original_block);
+            }
+            CurrentlyEditing::SynthDecay => {
+                key_block = Block::default()
+                    .title("Enter New Synth Decay (ms)")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Current Synth Decay")
+                    .borders(Borders::ALL);
+                original_text =
+                    Paragraph::new(app.get_synth_decay().to_string()).block(original_block);
+            }
+            CurrentlyEditing::SamplePitch => {
+                key_block = Block::default()
+                    .title("Enter New Sample Pitch (semitones)")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Current Sample Pitch")
+                    .borders(Borders::ALL);
+                original_text = Paragraph::new(app.get_sample_processing().pitch.to_string())
+                    .block(original_block);
+            }
+            CurrentlyEditing::LatencyOffset => {
+                key_block = Block::default()
+                    .title("Enter New Latency Offset (ms)")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Current Latency Offset")
+                    .borders(Borders::ALL);
+                original_text =
+                    Paragraph::new(app.get_latency_offset().to_string()).block(original_block);
+            }
+            CurrentlyEditing::SoundPath => {
+                key_block = Block::default()
+                    .title("Enter Path to Sound File")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Supported Sounds")
+                    .borders(Borders::ALL);
+                original_text = Paragraph::new(
+                    "WAV, FLAC, OGG or MP3 under ".to_owned()
+                        + &MAX_SOUND_LENGTH.as_secs().to_string()
+                        + " seconds",
+                )
+                .block(original_block);
+            }
+            CurrentlyEditing::Section(index) => {
+                key_block = Block::default()
+                    .title("Enter Section (bars x time signature @bpm \"name\")")
+                    .borders(Borders::ALL);
+                original_block = Block::default()
+                    .title("Current Section")
+                    .borders(Borders::ALL);
+                original_text = Paragraph::new(app.get_section_line(index)).block(original_block);
+            }
         }
         // get the current state of the edit_string for display while editing
         let key_text =
@@ -188,6 +332,16 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         CurrentScreen::SoundSelection => {
             Span::styled("Sound Selection Mode", Style::default().fg(Color::Yellow))
         }
+        CurrentScreen::SongMap => Span::styled("Song Map Mode", Style::default().fg(Color::Yellow)),
+        CurrentScreen::AudioSettings => {
+            Span::styled("Audio Output Mode", Style::default().fg(Color::Yellow))
+        }
+        CurrentScreen::AudioError => {
+            Span::styled("AUDIO ERROR", Style::default().fg(Color::LightRed))
+        }
+        CurrentScreen::Calibration => {
+            Span::styled("Calibration Mode", Style::default().fg(Color::Yellow))
+        }
         CurrentScreen::Exiting => {
             Span::styled("Really Quit?", Style::default().fg(Color::LightRed))
         }
@@ -198,12 +352,17 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     let mode_footer = Paragraph::new(Line::from(current_navigation_text))
         .block(Block::default().borders(Borders::ALL));
 
-    // This displays the current keys the user can use
-    // TODO: sometimes this text is scrolled off the screen on smaller terminals, figure out how to scroll it
-    let current_keys_hint = {
+    // This displays the current keys the user can use, or a notification if there is one to show
+    // On smaller terminals this can be cut off, the help overlay (?) lists every key in a scrollable pop up
+    let current_keys_hint = if app.currently_editing.is_none() && !app.alert_string.is_empty() {
+        Span::styled(
+            app.alert_string.clone(),
+            Style::default().fg(Color::LightRed),
+        )
+    } else {
         match app.current_screen {
             CurrentScreen::Main => Span::styled(
-                "Use (arrow keys) to navigate, (enter) to select an option, or (q) to quit",
+                "Use (arrow keys) to navigate, (enter) to select an option, (?) for every key, or (q) to quit",
                 Style::default().fg(Color::Green),
             ),
             CurrentScreen::Editing => {
@@ -214,8 +373,31 @@ pub fn ui(f: &mut Frame, app: &mut App) {
                 }
             }
             CurrentScreen::SoundSelection => {
-                Span::styled("Use (arrow keys) to navigate, (enter) to select, (esc) to go back to edit menu, or (q) to quit", Style::default().fg(Color::Yellow))
+                if app.currently_editing.is_some() {
+                    Span::styled("Type the path to a sound file. Press (enter) to add it or (esc) to discard changes", Style::default().fg(Color::Yellow))
+                } else {
+                    Span::styled("Use (arrow keys) to navigate, (enter) to select, (esc) to go back to edit menu, or (q) to quit", Style::default().fg(Color::Yellow))
+                }
+            },
+            CurrentScreen::SongMap => {
+                if app.currently_editing.is_some() {
+                    Span::styled("Edit the section, clear it to remove it. Press (enter) to save or (esc) to discard changes", Style::default().fg(Color::Yellow))
+                } else {
+                    Span::styled("Use (arrow keys) to navigate, (enter) to edit a section, (esc) to go back to edit menu, or (q) to quit", Style::default().fg(Color::Yellow))
+                }
             },
+            CurrentScreen::AudioSettings => Span::styled(
+                "Use (arrow keys) to navigate, (enter) to choose the host or device, (esc) to go back to edit menu, or (q) to quit",
+                Style::default().fg(Color::Yellow),
+            ),
+            CurrentScreen::AudioError => Span::styled(
+                "(enter) to choose another device, (r) to try again, or (q) to quit",
+                Style::default().fg(Color::LightRed),
+            ),
+            CurrentScreen::Calibration => Span::styled(
+                "Tap (space) along with the click, (enter) to use the suggested offset, (esc) to go back to edit menu",
+                Style::default().fg(Color::Yellow),
+            ),
             CurrentScreen::Exiting => Span::styled(
                 "(q) to quit / (n) to return to main menu",
                 Style::default().fg(Color::Red),
@@ -235,12 +417,69 @@ pub fn ui(f: &mut Frame, app: &mut App) {
     let footer_chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(25), Constraint::Percentage(75)])
-        .split(chunks[2]);
+        .split(chunks[3]);
 
     // Render the footer
     f.render_widget(mode_footer, footer_chunks[0]);
     f.render_widget(key_notes_footer, footer_chunks[1]);
 
+    // Audio Error Pop Up ----------------------------------------------------------------------------------------------
+    // the audio device couldn't be opened, the metronome waits for another device to be chosen rather than quitting
+    if app.current_screen == CurrentScreen::AudioError {
+        f.render_widget(Clear, area);
+        let error_block = Block::default()
+            .title("Audio Device Problem")
+            .borders(Borders::ALL)
+            .style(Style::default().bg(Color::Black));
+        let error_text = Paragraph::new(vec![
+            Line::from(Span::styled(
+                app.get_audio_error().unwrap_or_default(),
+                error_style,
+            )),
+            Line::from(""),
+            Line::from("Output: ".to_owned() + &app.get_audio_output().label()),
+            Line::from(""),
+            Line::from("Press (enter) to choose another device, (r) to try again, or (q) to quit"),
+        ])
+        .wrap(Wrap { trim: true })
+        .block(error_block);
+        f.render_widget(error_text, area);
+    }
+
+    // Calibration Pop Up ----------------------------------------------------------------------------------------------
+    // tapping along with what you hear measures how late the click reaches your ears, which becomes the latency offset
+    if app.current_screen == CurrentScreen::Calibration {
+        f.render_widget(Clear, area);
+        let calibration_block = Block::default()
+            .title("Latency Calibration")
+            .borders(Borders::ALL)
+            .style(Style::default().bg(Color::Black));
+        let taps = app.calibration_taps.len();
+        let suggestion = match app.get_calibration_offset() {
+            Some(offset) => Span::styled(
+                "Suggested offset: ".to_owned() + &offset.to_string() + " ms",
+                Style::default().fg(Color::Green),
+            ),
+            None => Span::raw(
+                "Keep tapping, ".to_owned()
+                    + &(MIN_CALIBRATION_TAPS - taps).to_string()
+                    + " more to go",
+            ),
+        };
+        let calibration_text = Paragraph::new(vec![
+            Line::from("Press (space) in time with the click as you hear it."),
+            Line::from(""),
+            Line::from(
+                "Current offset: ".to_owned() + &app.get_latency_offset().to_string() + " ms",
+            ),
+            Line::from("Taps: ".to_owned() + &taps.to_string()),
+            Line::from(suggestion),
+        ])
+        .wrap(Wrap { trim: true })
+        .block(calibration_block);
+        f.render_widget(calibration_text, area);
+    }
+
     // Error Pop Up ----------------------------------------------------------------------------------------------------
     // hopefully no one will be seeing this :) this error pop's up if app.settings.error gets set to true by the metronome
     if app.current_screen == CurrentScreen::Error {
@@ -260,6 +499,114 @@ pub fn ui(f: &mut Frame, app: &mut App) {
         .block(error_block);
         f.render_widget(error_text, error_layout[0]);
     }
+
+    help(f, app);
+}
+
+// Help overlay --------------------------------------------------------------------------------------------------------
+// every key of the current screen, listed from the keymap and scrollable for when the terminal is too small to show
+// them all
+fn help(f: &mut Frame, app: &mut App) {
+    let Some(scroll) = app.help_scroll else {
+        return;
+    };
+    let help_lines = keymap::help_lines(&app.current_screen);
+    let keys_width = help_lines
+        .iter()
+        .map(|(keys, _)| keys.chars().count())
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let text: Vec<Line> = help_lines
+        .into_iter()
+        .map(|(keys, description)| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{:<width$}", keys, width = keys_width),
+                    Style::default().fg(Color::LightYellow),
+                ),
+                Span::raw(description),
+            ])
+        })
+        .collect();
+
+    // keep the scroll from going past the last line
+    let area = centered_rect(70, 70, f.area());
+    let visible_rows = area.height.saturating_sub(2);
+    let scroll = scroll.min((text.len() as u16).saturating_sub(visible_rows));
+    app.help_scroll = Some(scroll);
+
+    let help_block = Block::default()
+        .title("Keys (up / down to scroll, esc to close)")
+        .borders(Borders::ALL)
+        .style(Style::default().bg(Color::Black));
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(text).block(help_block).scroll((scroll, 0)),
+        area,
+    );
+}
+
+// Stage mode ----------------------------------------------------------------------------------------------------------
+// the tempo, time signature and bar.beat in digits as big as the terminal allows, to be read from behind a drum kit
+fn stage(f: &mut Frame, app: &mut App) {
+    let lines = app.get_stage_lines();
+    let flash = app.get_beat_indicator().flash;
+    let (width, height) = app.terminal_size;
+    let stage_height = height.saturating_sub(1); // the bottom row is kept for the key hints
+    let line_refs: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let scale = big_text::fit_scale(&line_refs, width, stage_height);
+    let colors = [Color::Green, Color::LightYellow, Color::LightCyan];
+
+    let mut text = Vec::new();
+    for (index, (line, color)) in lines.iter().zip(colors).enumerate() {
+        // a terminal too small for even the smallest digits gets plain text
+        if scale == 0 {
+            text.push(Line::from(Span::styled(
+                line.clone(),
+                Style::default().fg(color),
+            )));
+            continue;
+        }
+        if index > 0 {
+            text.extend((0..scale).map(|_| Line::from("")));
+        }
+        for row in big_text::render(line, scale) {
+            text.push(Line::from(Span::styled(row, Style::default().fg(color))));
+        }
+    }
+    let padding = stage_height.saturating_sub(text.len() as u16) / 2;
+    let mut padded = vec![Line::from(""); padding as usize];
+    padded.append(&mut text);
+
+    let stage_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .split(f.area());
+    let background = match flash {
+        true => Style::default().bg(Color::White),
+        false => Style::default(),
+    };
+    f.render_widget(Clear, f.area());
+    f.render_widget(
+        Paragraph::new(padded)
+            .alignment(Alignment::Center)
+            .style(background),
+        stage_layout[0],
+    );
+    let mut hint_spans = Vec::new();
+    if app.get_muted() {
+        hint_spans.push(Span::styled(
+            "MUTED | ",
+            Style::default().fg(Color::LightRed),
+        ));
+    }
+    hint_spans.push(Span::styled(
+        "(s) to leave stage mode, (t) to start / stop, (m) to mute, (+ / -) to change the bpm, (?) for help, or (q) to quit",
+        Style::default().fg(Color::DarkGray),
+    ));
+    let hint = Paragraph::new(Line::from(hint_spans)).alignment(Alignment::Center);
+    f.render_widget(hint, stage_layout[1]);
 }
 
 /// helper function to create a centered rect using up certain percentage of the available rect `r`